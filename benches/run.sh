@@ -0,0 +1,26 @@
+#!/usr/bin/env bash
+# Runs each benchmark program with a release build and prints its best time
+# out of three runs, in seconds.
+#
+# Usage: [FEATURES=nan-boxing] benches/run.sh [program.lox...]
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+cargo build --quiet --release --no-default-features --features "${FEATURES:-}"
+
+TIMEFORMAT=%R
+programs=("$@")
+if [ ${#programs[@]} -eq 0 ]; then
+    programs=(benches/*.lox)
+fi
+
+for program in "${programs[@]}"; do
+    best=
+    for _ in 1 2 3; do
+        seconds=$( { time target/release/rlox "$program" > /dev/null; } 2>&1 )
+        if [ -z "$best" ] || awk "BEGIN { exit !($seconds < $best) }"; then
+            best=$seconds
+        fi
+    done
+    printf '%-24s %ss\n' "$(basename "$program")" "$best"
+done
@@ -0,0 +1,178 @@
+use crate::compiler::GlobalBindings;
+use crate::types::{Origin, Type, TypeExpr};
+
+/// Something the checker verifies once the program has been parsed, at the
+/// line the compiler was on when it recorded it.
+#[derive(Debug)]
+pub(crate) struct Check {
+    pub(crate) line: usize,
+    pub(crate) kind: CheckKind,
+}
+
+#[derive(Debug)]
+pub(crate) enum CheckKind {
+    /// A value stored where `expected` is declared. `what` names the place,
+    /// like "Variable 'x'".
+    Store {
+        what: String,
+        expected: TypeExpr,
+        value: TypeExpr,
+    },
+    /// The operands of a `Negate` or `Binary` expression.
+    Operands(TypeExpr),
+    /// The arguments of a call, against the parameters of the callee.
+    Arguments {
+        callee: TypeExpr,
+        positional: Vec<TypeExpr>,
+        named: Vec<(String, TypeExpr)>,
+    },
+    /// An assignment to a global not declared yet, which must not turn out
+    /// to be a constant.
+    Assign(String),
+}
+
+/// Runs `checks`, returning the line and message of every type error.
+pub(crate) fn check(checks: &[Check], globals: &GlobalBindings) -> Vec<(usize, String)> {
+    let mut checker = Checker {
+        globals,
+        resolving: Vec::new(),
+    };
+    checks
+        .iter()
+        .filter_map(|check| checker.check(&check.kind).map(|msg| (check.line, msg)))
+        .collect()
+}
+
+struct Checker<'a> {
+    globals: &'a GlobalBindings,
+    /// Globals whose type is being resolved, to stop at constants defined in
+    /// terms of each other.
+    resolving: Vec<String>,
+}
+
+impl Checker<'_> {
+    fn check(&mut self, kind: &CheckKind) -> Option<String> {
+        match kind {
+            CheckKind::Store {
+                what,
+                expected,
+                value,
+            } => {
+                let expected = self.resolve(expected);
+                let value = self.resolve(value);
+                self.store(what, expected, value)
+            }
+            CheckKind::Operands(TypeExpr::Negate(operand)) => {
+                let (ty, origin) = self.resolve(operand);
+                match origin {
+                    Origin::Annotated => ty.negate().err(),
+                    Origin::Inferred => None,
+                }
+            }
+            CheckKind::Operands(TypeExpr::Binary(symbol, left, right)) => {
+                let (left, left_origin) = self.resolve(left);
+                let (right, right_origin) = self.resolve(right);
+                match left_origin.max(right_origin) {
+                    Origin::Annotated => Type::binary(symbol, &left, &right).err(),
+                    Origin::Inferred => None,
+                }
+            }
+            CheckKind::Operands(_) => None,
+            CheckKind::Assign(name) if self.globals.is_const(name) => {
+                Some(format!("Can't assign to constant '{}'.", name))
+            }
+            CheckKind::Assign(_) => None,
+            CheckKind::Arguments {
+                callee,
+                positional,
+                named,
+            } => {
+                let signature = match self.resolve(callee) {
+                    (Type::Function(Some(signature)), _) => signature,
+                    _ => return None,
+                };
+                let args = positional
+                    .iter()
+                    .zip(&signature.params)
+                    .map(|(arg, param)| (arg, Some(param)))
+                    .chain(named.iter().map(|(name, arg)| {
+                        let param = signature.params.iter().find(|(param, _)| param == name);
+                        (arg, param)
+                    }));
+                for (arg, param) in args {
+                    let (name, ty) = match param {
+                        Some(param) => param,
+                        None => continue,
+                    };
+                    let what = match &signature.name {
+                        Some(function) => format!("Argument '{}' of '{}'", name, function),
+                        None => format!("Argument '{}'", name),
+                    };
+                    let value = self.resolve(arg);
+                    let msg = self.store(&what, (ty.clone(), Origin::Annotated), value);
+                    if msg.is_some() {
+                        return msg;
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// The error for storing `value` where `expected` is declared, unless
+    /// neither type comes from an annotation.
+    fn store(
+        &self,
+        what: &str,
+        (expected, expected_origin): (Type, Origin),
+        (value, value_origin): (Type, Origin),
+    ) -> Option<String> {
+        if expected_origin.max(value_origin) == Origin::Inferred
+            || expected.accepts(&value, &self.globals.classes)
+        {
+            return None;
+        }
+        Some(format!("{} must be {} but got {}.", what, expected, value))
+    }
+
+    fn resolve(&mut self, expr: &TypeExpr) -> (Type, Origin) {
+        let any = (Type::Any, Origin::Inferred);
+        match expr {
+            TypeExpr::Known(ty, origin) => (ty.clone(), *origin),
+            TypeExpr::Global(name) => {
+                let declared = match self.globals.declared_type(name) {
+                    Some(declared) if !self.resolving.contains(name) => declared.clone(),
+                    _ => return any,
+                };
+                self.resolving.push(name.clone());
+                let resolved = self.resolve(&declared);
+                self.resolving.pop();
+                resolved
+            }
+            TypeExpr::Call(callee) => match self.resolve(callee) {
+                (Type::Function(Some(signature)), _) => (signature.ret.clone(), Origin::Annotated),
+                (Type::ClassObject(class), _) => (Type::Class(class), Origin::Inferred),
+                _ => any,
+            },
+            TypeExpr::Negate(operand) => {
+                let (ty, origin) = self.resolve(operand);
+                ty.negate().map_or(any, |ty| (ty, origin))
+            }
+            TypeExpr::Binary(symbol, left, right) => {
+                let (left, left_origin) = self.resolve(left);
+                let (right, right_origin) = self.resolve(right);
+                Type::binary(symbol, &left, &right)
+                    .map_or(any, |ty| (ty, left_origin.max(right_origin)))
+            }
+            TypeExpr::Coalesce(left, right) => {
+                let (left, left_origin) = self.resolve(left);
+                let (right, right_origin) = self.resolve(right);
+                if left == right {
+                    (left, left_origin.max(right_origin))
+                } else {
+                    any
+                }
+            }
+        }
+    }
+}
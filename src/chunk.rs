@@ -1,15 +1,19 @@
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
+use lazy_static::lazy_static;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use line_encoding::LineEncoding;
 
-use crate::value::{ConstArray, Value};
+use crate::error::{self, Result};
+use crate::value::{ConstArray, Slot, Value};
+use snafu::OptionExt;
+use std::convert::TryFrom;
 use std::fmt::Display;
 
 mod line_encoding;
 
-#[derive(Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
     OpReturn = 0,
@@ -23,10 +27,86 @@ pub enum OpCode {
     OpSubtract,
     OpMultiply,
     OpDivide,
+    OpModulo,
     OpNot,
     OpEqual,
     OpGreater,
     OpLess,
+    OpPrint,
+    OpPop,
+    OpDefineGlobal,
+    OpDefineGlobalLong,
+    OpGetGlobal,
+    OpGetGlobalLong,
+    OpSetGlobal,
+    OpSetGlobalLong,
+    OpGetLocal,
+    OpSetLocal,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpCloseUpvalue,
+    OpClosure,
+    OpClosureLong,
+    OpCall,
+    OpCallNamed,
+    OpTailCall,
+    OpSkipIfArg,
+    OpYield,
+    OpGetProperty,
+    OpGetPropertyLong,
+    OpInvoke,
+    OpInvokeLong,
+    OpLoop,
+    OpForIter,
+    OpClass,
+    OpClassLong,
+    OpMethod,
+    OpMethodLong,
+    OpInherit,
+    OpSetProperty,
+    OpSetPropertyLong,
+    OpGetSuper,
+    OpGetSuperLong,
+    OpSuperInvoke,
+    OpSuperInvokeLong,
+    OpIndex,
+    OpTrait,
+    OpTraitLong,
+    OpUseTraits,
+    OpEndClass,
+    OpJump,
+    OpJumpIfFalse,
+    OpMatch,
+    OpNoMatch,
+    OpRange,
+    OpRangeInclusive,
+    OpJumpIfNil,
+    OpJumpIfNotNil,
+    OpAssertFail,
+    OpAssertFailLong,
+    OpBuildList,
+    OpSetIndex,
+    OpUnpackList,
+    OpUnpackFields,
+    OpBuildMap,
+}
+
+lazy_static! {
+    static ref OPCODES: [Option<OpCode>; 256] = {
+        let mut opcodes = [None; 256];
+        for (byte, opcode) in opcodes.iter_mut().enumerate() {
+            *opcode = OpCode::try_from(byte as u8).ok();
+        }
+        opcodes
+    };
+}
+
+impl OpCode {
+    /// The opcode `byte` stands for. A table lookup, cheaper than `try_from`
+    /// in the dispatch loop.
+    pub fn decode(byte: u8) -> Option<OpCode> {
+        OPCODES[byte as usize]
+    }
 }
 
 impl Display for OpCode {
@@ -35,6 +115,7 @@ impl Display for OpCode {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: ConstArray,
@@ -61,14 +142,18 @@ impl Chunk {
     }
 
     pub fn write_constant(&mut self, value: Value, line: u32) {
-        use OpCode::*;
+        self.write_indexed(OpCode::OpConstant, OpCode::OpConstantLong, value, line)
+    }
 
+    /// Adds `value` to the constant table and writes `op` followed by its index,
+    /// or `long_op` followed by a 24 bit index if it doesn't fit in a byte.
+    pub fn write_indexed(&mut self, op: OpCode, long_op: OpCode, value: Value, line: u32) {
         let addr = self.add_constant(value);
-        if addr <= u8::max_value() as usize {
-            self.write(OpConstant as u8, line);
+        if addr <= u8::MAX as usize {
+            self.write(op as u8, line);
             self.write(addr as u8, line);
         } else {
-            self.write(OpConstantLong as u8, line);
+            self.write(long_op as u8, line);
             for b in write_u24(addr as u32) {
                 self.write(b, line)
             }
@@ -82,6 +167,247 @@ impl Chunk {
     pub fn len(&self) -> usize {
         self.code.len()
     }
+
+    /// Drops the code from `len` on. Constants it used stay in the table.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+    }
+
+    /// Checks the code from `start` on once, before the VM runs it, so the
+    /// dispatch loop can read it without bounds checks. Every opcode must be
+    /// known, with its operands inside the code; constant operands must exist
+    /// and have the right kind; jumps must land on an instruction after
+    /// `start`; and the code must end with `OpReturn`, so it never runs off
+    /// the end. The functions it makes closures of are checked as well.
+    pub(crate) fn verify(&self, start: usize, upvalue_count: usize) -> Result<()> {
+        let mut instructions = vec![false; self.code.len()];
+        let mut targets = Vec::new();
+        let mut last = None;
+        let mut operands = Operands {
+            chunk: self,
+            offset: start,
+        };
+        while operands.offset < self.code.len() {
+            let offset = operands.offset;
+            instructions[offset] = true;
+            let byte = operands.byte()?;
+            let op = OpCode::decode(byte).with_context(|| error::NoOpCodeError {
+                msg: format!("unknown opcode {} at {}", byte, offset),
+            })?;
+            match op {
+                OpCode::OpConstant => {
+                    operands.constant(false)?;
+                }
+                OpCode::OpConstantLong => {
+                    operands.constant(true)?;
+                }
+                OpCode::OpDefineGlobal
+                | OpCode::OpGetGlobal
+                | OpCode::OpSetGlobal
+                | OpCode::OpGetProperty
+                | OpCode::OpSetProperty
+                | OpCode::OpGetSuper
+                | OpCode::OpClass
+                | OpCode::OpTrait
+                | OpCode::OpAssertFail => operands.name(false)?,
+                OpCode::OpDefineGlobalLong
+                | OpCode::OpGetGlobalLong
+                | OpCode::OpSetGlobalLong
+                | OpCode::OpGetPropertyLong
+                | OpCode::OpSetPropertyLong
+                | OpCode::OpGetSuperLong
+                | OpCode::OpClassLong
+                | OpCode::OpTraitLong
+                | OpCode::OpAssertFailLong => operands.name(true)?,
+                OpCode::OpMethod | OpCode::OpInvoke | OpCode::OpSuperInvoke => {
+                    operands.name(false)?;
+                    operands.byte()?;
+                }
+                OpCode::OpMethodLong | OpCode::OpInvokeLong | OpCode::OpSuperInvokeLong => {
+                    operands.name(true)?;
+                    operands.byte()?;
+                }
+                OpCode::OpGetUpvalue | OpCode::OpSetUpvalue => {
+                    let index = operands.byte()? as usize;
+                    if index >= upvalue_count {
+                        return error::NoOpCodeError {
+                            msg: format!("upvalue {} at {}", index, offset),
+                        }
+                        .fail();
+                    }
+                }
+                OpCode::OpGetLocal
+                | OpCode::OpSetLocal
+                | OpCode::OpCall
+                | OpCode::OpTailCall
+                | OpCode::OpUseTraits
+                | OpCode::OpBuildList
+                | OpCode::OpBuildMap
+                | OpCode::OpNoMatch => {
+                    operands.byte()?;
+                }
+                OpCode::OpUnpackList => {
+                    operands.byte()?;
+                    operands.byte()?;
+                }
+                OpCode::OpClosure | OpCode::OpClosureLong => {
+                    let constant = operands.constant(op == OpCode::OpClosureLong)?;
+                    let function =
+                        constant
+                            .as_function()
+                            .ok()
+                            .with_context(|| error::NoOpCodeError {
+                                msg: format!("closure of a non-function at {}", offset),
+                            })?;
+                    for _ in 0..function.upvalue_count {
+                        let is_local = operands.byte()?;
+                        let index = operands.byte()? as usize;
+                        if is_local > 1 || (is_local == 0 && index >= upvalue_count) {
+                            return error::NoOpCodeError {
+                                msg: format!("captured upvalue {} at {}", index, offset),
+                            }
+                            .fail();
+                        }
+                    }
+                    function.chunk.verify(0, function.upvalue_count)?;
+                }
+                OpCode::OpCallNamed => {
+                    operands.byte()?;
+                    for _ in 0..operands.byte()? {
+                        operands.name(true)?;
+                    }
+                }
+                OpCode::OpUnpackFields => {
+                    for _ in 0..operands.byte()? {
+                        operands.name(true)?;
+                    }
+                }
+                OpCode::OpJump
+                | OpCode::OpJumpIfFalse
+                | OpCode::OpJumpIfNil
+                | OpCode::OpJumpIfNotNil => {
+                    let jump = operands.short()?;
+                    targets.push(operands.offset + jump);
+                }
+                OpCode::OpSkipIfArg | OpCode::OpForIter => {
+                    operands.byte()?;
+                    let jump = operands.short()?;
+                    targets.push(operands.offset + jump);
+                }
+                OpCode::OpMatch => {
+                    operands.byte()?;
+                    let pattern = operands.constant(true)?;
+                    if !pattern.is_pattern() {
+                        return error::NoOpCodeError {
+                            msg: format!("match on a non-pattern at {}", offset),
+                        }
+                        .fail();
+                    }
+                    let jump = operands.short()?;
+                    targets.push(operands.offset + jump);
+                }
+                OpCode::OpLoop => {
+                    let jump = operands.short()?;
+                    let target = operands.offset.checked_sub(jump);
+                    targets.push(target.with_context(|| error::NoOpCodeError {
+                        msg: format!("loop before the code at {}", offset),
+                    })?);
+                }
+                OpCode::OpReturn
+                | OpCode::OpNil
+                | OpCode::OpTrue
+                | OpCode::OpFalse
+                | OpCode::OpNegate
+                | OpCode::OpAdd
+                | OpCode::OpSubtract
+                | OpCode::OpMultiply
+                | OpCode::OpDivide
+                | OpCode::OpModulo
+                | OpCode::OpNot
+                | OpCode::OpEqual
+                | OpCode::OpGreater
+                | OpCode::OpLess
+                | OpCode::OpPrint
+                | OpCode::OpPop
+                | OpCode::OpCloseUpvalue
+                | OpCode::OpYield
+                | OpCode::OpInherit
+                | OpCode::OpIndex
+                | OpCode::OpEndClass
+                | OpCode::OpRange
+                | OpCode::OpRangeInclusive
+                | OpCode::OpSetIndex => {}
+            }
+            last = Some(op);
+        }
+
+        if last != Some(OpCode::OpReturn) {
+            return error::NoOpCodeError {
+                msg: "code doesn't end with OpReturn",
+            }
+            .fail();
+        }
+        for target in targets {
+            if target < start || !instructions.get(target).copied().unwrap_or(false) {
+                return error::NoOpCodeError {
+                    msg: format!("jump to {}, which starts no instruction", target),
+                }
+                .fail();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the operands of the instruction `Chunk::verify` is at.
+struct Operands<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl<'a> Operands<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .chunk
+            .code
+            .get(self.offset)
+            .with_context(|| error::NoOpCodeError {
+                msg: format!("operand at {} past the end of the code", self.offset),
+            })?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn short(&mut self) -> Result<usize> {
+        Ok((self.byte()? as usize) << 8 | self.byte()? as usize)
+    }
+
+    /// A constant index operand, one byte or three for `long` ops.
+    fn constant(&mut self, long: bool) -> Result<&'a Slot> {
+        let index = if long {
+            (self.byte()? as usize) << 16 | (self.byte()? as usize) << 8 | self.byte()? as usize
+        } else {
+            self.byte()? as usize
+        };
+        self.chunk
+            .constants
+            .get(index)
+            .with_context(|| error::NoOpCodeError {
+                msg: format!("constant {} at {}", index, self.offset),
+            })
+    }
+
+    /// A constant operand naming a variable, property or method.
+    fn name(&mut self, long: bool) -> Result<()> {
+        if !self.constant(long)?.is_str() {
+            return error::NoOpCodeError {
+                msg: format!("name that isn't a string at {}", self.offset),
+            }
+            .fail();
+        }
+        Ok(())
+    }
 }
 
 pub(crate) fn write_u24(n: u32) -> Vec<u8> {
@@ -91,10 +417,10 @@ pub(crate) fn write_u24(n: u32) -> Vec<u8> {
     buf[1..].to_vec()
 }
 
+/// Reads a u24 from a 4 byte big endian buffer whose first byte must be zero.
 pub(crate) fn read_u24(buf: &[u8]) -> u32 {
-    assert!(buf.len() >= 3);
-    let buf2 = vec![0, buf[0], buf[1], buf[2]];
-    let n = BigEndian::read_u32(&buf2);
+    assert!(buf.len() >= 4);
+    let n = BigEndian::read_u32(buf);
     assert!(n <= 0xffffff);
     n
 }
@@ -126,4 +452,45 @@ mod tests {
     fn test_write_u24_shoudl_panic() {
         assert_eq!(write_u24(0x1101010), vec![0, 0, 30]);
     }
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Nil);
+        for byte in code {
+            chunk.write(*byte, 1);
+        }
+        chunk
+    }
+
+    #[test]
+    fn test_verify() {
+        use OpCode::*;
+        let jump = [OpNil as u8, OpJumpIfFalse as u8, 0, 1, OpPop as u8];
+        assert!(chunk(&[&jump[..], &[OpReturn as u8]].concat())
+            .verify(0, 0)
+            .is_ok());
+        // Into the middle of an instruction.
+        let into_operand = [OpJump as u8, 0, 1, OpConstant as u8, 0, OpReturn as u8];
+        assert!(chunk(&into_operand).verify(0, 0).is_err());
+        // Past the end of the code.
+        assert!(chunk(&[OpLoop as u8, 0, 4, OpReturn as u8])
+            .verify(0, 0)
+            .is_err());
+        assert!(chunk(&[OpConstant as u8, 1, OpReturn as u8])
+            .verify(0, 0)
+            .is_err());
+        assert!(chunk(&[OpGetUpvalue as u8, 0, OpReturn as u8])
+            .verify(0, 0)
+            .is_err());
+        assert!(chunk(&[OpGetGlobal as u8, 0, OpReturn as u8])
+            .verify(0, 0)
+            .is_err());
+        assert!(chunk(&[0xff, OpReturn as u8]).verify(0, 0).is_err());
+        assert!(chunk(&[OpNil as u8, OpConstant as u8])
+            .verify(0, 0)
+            .is_err());
+        assert!(chunk(&[OpNil as u8]).verify(0, 0).is_err());
+        // Only the code from `start` on is checked.
+        assert!(chunk(&[0xff, OpReturn as u8]).verify(1, 0).is_ok());
+    }
 }
@@ -2,6 +2,7 @@ use byteorder::ByteOrder;
 use byteorder::LittleEndian;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 
+#[derive(Debug, Clone)]
 pub struct LineEncoding {
     buf: Vec<u8>,
 }
@@ -32,6 +33,21 @@ impl LineEncoding {
         self.buf.write_u8(1).expect("write count");
     }
 
+    /// Keeps the line numbers of the first `len` bytes only.
+    pub fn truncate(&mut self, len: usize) {
+        let mut covered = 0;
+        let mut offset = 0;
+        while offset < self.buf.len() && covered < len {
+            let count = self.buf[offset + 4] as usize;
+            if covered + count > len {
+                self.buf[offset + 4] = (len - covered) as u8;
+            }
+            covered += count;
+            offset += 5;
+        }
+        self.buf.truncate(offset);
+    }
+
     pub fn get(&self, index: usize) -> u32 {
         let mut buf = self.buf.as_slice();
 
@@ -72,6 +88,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_truncate() {
+        let mut encoding = LineEncoding::new();
+        for line in &[1, 10, 10, 10, 12, 12, 15] {
+            encoding.add(*line);
+        }
+        encoding.truncate(6);
+        assert_eq!(
+            encoding.buf,
+            vec![1, 0, 0, 0, 1, 10, 0, 0, 0, 3, 12, 0, 0, 0, 2]
+        );
+        encoding.truncate(2);
+        assert_eq!(encoding.buf, vec![1, 0, 0, 0, 1, 10, 0, 0, 0, 1]);
+        encoding.add(10);
+        assert_eq!(encoding.get(2), 10);
+        encoding.truncate(0);
+        assert!(encoding.buf.is_empty());
+    }
+
     #[test]
     fn test_get() {
         let mut encoding = LineEncoding::new();
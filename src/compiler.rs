@@ -1,23 +1,38 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::rc::Rc;
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use snafu::{OptionExt, ResultExt};
 
 use lazy_static::lazy_static;
+use num_bigint::BigInt;
 
-use crate::chunk::Chunk;
+use crate::checker::{self, Check, CheckKind};
 use crate::chunk::OpCode::{
-    OpAdd, OpDivide, OpEqual, OpFalse, OpGreater, OpLess, OpMultiply, OpNegate, OpNil, OpNot,
-    OpReturn, OpSubtract, OpTrue,
+    OpAdd, OpAssertFail, OpAssertFailLong, OpBuildList, OpBuildMap, OpCall, OpCallNamed, OpClass,
+    OpClassLong, OpCloseUpvalue, OpClosure, OpClosureLong, OpConstant, OpConstantLong,
+    OpDefineGlobal, OpDefineGlobalLong, OpDivide, OpEndClass, OpEqual, OpFalse, OpForIter,
+    OpGetGlobal, OpGetGlobalLong, OpGetLocal, OpGetProperty, OpGetPropertyLong, OpGetSuper,
+    OpGetSuperLong, OpGetUpvalue, OpGreater, OpIndex, OpInherit, OpInvoke, OpInvokeLong, OpJump,
+    OpJumpIfFalse, OpJumpIfNil, OpJumpIfNotNil, OpLess, OpLoop, OpMatch, OpMethod, OpMethodLong,
+    OpModulo, OpMultiply, OpNegate, OpNil, OpNoMatch, OpNot, OpPop, OpPrint, OpRange,
+    OpRangeInclusive, OpReturn, OpSetGlobal, OpSetGlobalLong, OpSetIndex, OpSetLocal,
+    OpSetProperty, OpSetPropertyLong, OpSetUpvalue, OpSkipIfArg, OpSubtract, OpSuperInvoke,
+    OpSuperInvokeLong, OpTailCall, OpTrait, OpTraitLong, OpTrue, OpUnpackFields, OpUnpackList,
+    OpUseTraits, OpYield,
 };
+use crate::chunk::{read_u24, write_u24, Chunk, OpCode};
 use crate::debug::disassemble;
 use crate::error::{self, Error, Result};
+use crate::object::{Enum, Function, MethodKind, Pattern};
 use crate::scanner::{Scanner, Token};
 use crate::token_type::TokenType;
+use crate::types::{Classes, Signature, Type, TypeExpr};
 use crate::value::Value;
 
-type ParseFn = fn(&mut Compiler) -> Result<()>;
+type ParseFn = fn(&mut Compiler, bool) -> Result<()>;
 
 #[derive(Clone)]
 struct ParseRule {
@@ -58,44 +73,66 @@ macro_rules! parse_rule {
 lazy_static! {
     static ref RULES: HashMap<TokenType, ParseRule> = parse_rule!(
         {
-            { TokenType::LeftParen, Some(grouping), None,    Precedence::Call },
+            { TokenType::LeftParen, Some(grouping), Some(call),    Precedence::Call },
             { TokenType::RightParen, None,     None,    Precedence::None },
-            { TokenType::LeftBrace, None,     None,    Precedence::None },
+            { TokenType::LeftBrace, Some(map),     None,    Precedence::None },
+            { TokenType::LeftBracket, Some(list),     Some(index),    Precedence::Call },
+            { TokenType::RightBracket, None,     None,    Precedence::None },
             { TokenType::RightBrace, None,     None,    Precedence::None },
             { TokenType::Comma, None,     None,    Precedence::None },
-            { TokenType::Dot, None,     None,    Precedence::Call },
+            { TokenType::Dot, None,     Some(dot),    Precedence::Call },
+            { TokenType::QuestionDot, None,     Some(optional_dot),    Precedence::Call },
+            { TokenType::QuestionQuestion, None,     Some(coalesce),    Precedence::Coalesce },
+            { TokenType::QuestionMark, None,     None,    Precedence::None },
+            { TokenType::Colon, None,     None,    Precedence::None },
             { TokenType::Minus, Some(unary),    Some(binary),  Precedence::Term },
             { TokenType::Plus, None,     Some(binary),  Precedence::Term },
             { TokenType::Semicolon, None,     None,    Precedence::None },
             { TokenType::Slash, None,     Some(binary),  Precedence::Factor },
             { TokenType::Star, None,     Some(binary),  Precedence::Factor },
+            { TokenType::Percent, None,     Some(binary),  Precedence::Factor },
             { TokenType::Bang, Some(unary),     None,    Precedence::None },
             { TokenType::BangEqual, None,     Some(binary),    Precedence::Equality },
             { TokenType::Equal, None,     None,    Precedence::None },
+            { TokenType::Arrow, None,     None,    Precedence::None },
             { TokenType::EqualEqual, None,     Some(binary),    Precedence::Equality },
             { TokenType::Greater, None,     Some(binary),    Precedence::Comparison },
             { TokenType::GreaterEqual, None,     Some(binary),    Precedence::Comparison },
             { TokenType::Less, None,     Some(binary),    Precedence::Comparison },
             { TokenType::LessEqual, None,     Some(binary),    Precedence::Comparison },
-            { TokenType::Identifier, None,     None,    Precedence::None },
+            { TokenType::DotDot, None,     Some(binary),    Precedence::Range },
+            { TokenType::DotDotEqual, None,     Some(binary),    Precedence::Range },
+            { TokenType::Identifier, Some(variable),     None,    Precedence::None },
             { TokenType::Str, Some(string),     None,    Precedence::None },
             { TokenType::Number, Some(number),   None,    Precedence::None },
             { TokenType::And, None,     None,    Precedence::And },
+            { TokenType::Assert, None,     None,    Precedence::None },
             { TokenType::Class, None,     None,    Precedence::None },
+            { TokenType::Const, None,     None,    Precedence::None },
             { TokenType::Else, None,     None,    Precedence::None },
             { TokenType::False, Some(literal),     None,    Precedence::None },
             { TokenType::For, None,     None,    Precedence::None },
-            { TokenType::Fun, None,     None,    Precedence::None },
+            { TokenType::Fun, Some(function_expression),     None,    Precedence::None },
             { TokenType::If, None,     None,    Precedence::None },
+            { TokenType::In, None,     None,    Precedence::None },
+            { TokenType::Match, Some(match_),     None,    Precedence::None },
             { TokenType::Nil, Some(literal),     None,    Precedence::None },
             { TokenType::Or, None,     None,    Precedence::Or },
             { TokenType::Print, None,     None,    Precedence::None },
             { TokenType::Return, None,     None,    Precedence::None },
-            { TokenType::Super, None,     None,    Precedence::None },
-            { TokenType::This, None,     None,    Precedence::None },
+            { TokenType::Super, Some(super_),     None,    Precedence::None },
+            { TokenType::This, Some(this),     None,    Precedence::None },
             { TokenType::True, Some(literal),     None,    Precedence::None },
             { TokenType::Var, None,     None,    Precedence::None },
             { TokenType::While, None,     None,    Precedence::None },
+            { TokenType::Yield, None,     None,    Precedence::None },
+            { TokenType::Get, Some(variable),     None,    Precedence::None },
+            { TokenType::Set, Some(variable),     None,    Precedence::None },
+            { TokenType::Static, Some(variable),     None,    Precedence::None },
+            { TokenType::Trait, Some(variable),     None,    Precedence::None },
+            { TokenType::With, Some(variable),     None,    Precedence::None },
+            { TokenType::Enum, Some(variable),     None,    Precedence::None },
+            { TokenType::Let, Some(variable),     None,    Precedence::None },
             { TokenType::Eof, None,     None,    Precedence::None },
         }
     );
@@ -116,10 +153,12 @@ enum TokenPosition {
 enum Precedence {
     None,
     Assignment, // =
+    Coalesce,   // ??
     Or,         // or
     And,        // and
     Equality,   // == !=
     Comparison, // < > <= >=
+    Range,      // .. ..=
     Term,       // + -
     Factor,     // * /
     Unary,      // ! -
@@ -132,7 +171,8 @@ struct Parser<'a> {
     scanner: Scanner<'a>,
     current: Option<Token>,
     previous: Option<Token>,
-    had_error: bool,
+    /// The line and message of the first error reported.
+    first_error: Option<(usize, String)>,
     panic_mode: bool,
 }
 
@@ -142,7 +182,7 @@ impl<'a> Parser<'a> {
             scanner,
             current: None,
             previous: None,
-            had_error: false,
+            first_error: None,
             panic_mode: false,
         }
     }
@@ -174,12 +214,52 @@ impl<'a> Parser<'a> {
             _ => eprint!(" at '{}'", String::from_utf8_lossy(&token.lexeme)),
         }
         eprintln!(": {}", msg);
-        self.had_error = true;
+        let line = token.line;
+        self.record_error(line, msg);
         Ok(())
     }
 
+    /// Reports a problem at the previous token that doesn't stop compilation.
+    fn warning(&mut self, msg: &str) -> Result<()> {
+        let token = self.previous()?;
+        eprintln!(
+            "[line {}] Warning at '{}': {}",
+            token.line,
+            String::from_utf8_lossy(&token.lexeme),
+            msg
+        );
+        Ok(())
+    }
+
+    /// Reports an error the checker found after parsing.
+    fn type_error(&mut self, line: usize, msg: &str) {
+        eprintln!("[line {}] Error: {}", line, msg);
+        self.record_error(line, msg);
+    }
+
+    // The scanner produces no token for malformed input, so report it by line.
+    fn scan_error(&mut self, line: usize, msg: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+
+        eprintln!("[line {}] Error: {}", line, msg);
+        self.record_error(line, msg);
+    }
+
+    fn record_error(&mut self, line: usize, msg: &str) {
+        if self.first_error.is_none() {
+            self.first_error = Some((line, msg.to_string()));
+        }
+    }
+
+    fn had_error(&self) -> bool {
+        self.first_error.is_some()
+    }
+
     fn consume(&mut self, ty: TokenType, msg: &str) -> Result<()> {
-        if self.current()?.ty == ty {
+        if self.check(ty)? {
             self.advance()?;
             return Ok(());
         }
@@ -196,8 +276,8 @@ impl<'a> Parser<'a> {
                     self.current = Some(t);
                     return Ok(());
                 }
-                Err(Error::ScanError { msg, .. }) => {
-                    self.error_at_current(&msg)?;
+                Err(Error::ScanError { msg, line }) => {
+                    self.scan_error(line, &msg);
                 }
                 Err(e) => {
                     eprintln!("unknown error {:?}", e);
@@ -206,6 +286,106 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Contextual keywords also count as identifiers.
+    fn check(&self, ty: TokenType) -> Result<bool> {
+        let current = self.current()?.ty;
+        Ok(current == ty || (ty == TokenType::Identifier && current.is_identifier()))
+    }
+
+    fn match_token(&mut self, ty: TokenType) -> Result<bool> {
+        if !self.check(ty)? {
+            return Ok(false);
+        }
+        self.advance()?;
+        Ok(true)
+    }
+
+    /// Whether the tokens after an opening `(` are an arrow function's
+    /// parameter list, `(a, b: Number = 1): Number =>`. Scans ahead without
+    /// consuming anything.
+    fn at_arrow_params(&self) -> Result<bool> {
+        let mut scanner = self.scanner.clone();
+        let mut next = || scanner.scan_token().map(|t| t.ty).ok();
+
+        let mut ty = Some(self.current()?.ty);
+        loop {
+            if ty == Some(TokenType::DotDotDot) {
+                ty = next();
+            }
+            if !ty.is_some_and(TokenType::is_identifier) {
+                break;
+            }
+            ty = next();
+            if ty == Some(TokenType::Colon) {
+                next();
+                ty = next();
+            }
+            if ty == Some(TokenType::Equal) {
+                // Skip the default value, up to the `,` or `)` that ends it.
+                let mut depth = 0;
+                loop {
+                    ty = next();
+                    match ty {
+                        None | Some(TokenType::Eof) => return Ok(false),
+                        Some(TokenType::LeftParen) => depth += 1,
+                        Some(TokenType::RightParen) | Some(TokenType::Comma) if depth == 0 => break,
+                        Some(TokenType::RightParen) => depth -= 1,
+                        _ => {}
+                    }
+                }
+            }
+            if ty != Some(TokenType::Comma) {
+                break;
+            }
+            ty = next();
+        }
+        if ty != Some(TokenType::RightParen) {
+            return Ok(false);
+        }
+        ty = next();
+        if ty == Some(TokenType::Colon) {
+            next();
+            ty = next();
+        }
+        Ok(ty == Some(TokenType::Arrow))
+    }
+
+    /// The type of the token after the current one, without consuming anything.
+    fn peek_next(&self) -> Option<TokenType> {
+        self.scanner.clone().scan_token().map(|t| t.ty).ok()
+    }
+
+    fn synchronize(&mut self) -> Result<()> {
+        self.panic_mode = false;
+
+        while self.current()?.ty != TokenType::Eof {
+            if self.previous()?.ty == TokenType::Semicolon {
+                return Ok(());
+            }
+            match self.current()?.ty {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::Const
+                | TokenType::Let
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Yield => return Ok(()),
+                _ => self.advance()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The source text from byte `start` to the end of the previous token.
+    fn source_since(&self, start: usize) -> Result<&'a [u8]> {
+        let previous = self.previous()?;
+        Ok(&self.scanner.source()[start..previous.start + previous.lexeme.len()])
+    }
+
     fn previous(&self) -> Result<&Token> {
         self.previous.as_ref().context(error::ParseError {
             msg: "no previous token",
@@ -223,35 +403,272 @@ impl<'a> Parser<'a> {
     }
 }
 
+/// What the compiler knows about a variable.
+#[derive(Debug, Clone)]
+pub(crate) enum Binding {
+    Var,
+    /// A `const`. Holds the value when the initializer was a literal, so uses
+    /// can load it directly instead of looking the variable up.
+    Const(Option<Value>),
+}
+
+/// A global declaration seen so far.
+#[derive(Debug, Clone)]
+pub(crate) struct Global {
+    binding: Binding,
+    ty: TypeExpr,
+}
+
+/// Global declarations seen so far. Kept by the VM so constants stay constant
+/// and annotations stay checked across REPL lines.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GlobalBindings {
+    vars: HashMap<Vec<u8>, Global>,
+    /// Every class declared, at any scope, for checking class types.
+    pub(crate) classes: Classes,
+}
+
+impl GlobalBindings {
+    /// Whether the global `name` is a constant, for the checker.
+    pub(crate) fn is_const(&self, name: &str) -> bool {
+        matches!(
+            self.vars.get(name.as_bytes()),
+            Some(Global {
+                binding: Binding::Const(_),
+                ..
+            })
+        )
+    }
+
+    /// The type of the global `name`, for the checker.
+    pub(crate) fn declared_type(&self, name: &str) -> Option<&TypeExpr> {
+        self.vars.get(name.as_bytes()).map(|global| &global.ty)
+    }
+}
+
+struct Local {
+    name: Vec<u8>,
+    /// `None` while the initializer is being compiled. Such a local has no
+    /// stack slot yet: values the initializer keeps on the stack, like the
+    /// hidden local of a `match`, take the slots from where its value will go.
+    depth: Option<usize>,
+    binding: Binding,
+    /// Declared type, or for a constant without an annotation the type of
+    /// its value.
+    ty: TypeExpr,
+    /// Captured by a closure, so it must be closed over when it goes out of scope.
+    captured: bool,
+    /// Expression temporaries under this local's slot, which only the locals
+    /// of a `match` inside a larger expression have.
+    temporaries: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+/// Where a variable lives at runtime.
+#[derive(Debug, Copy, Clone)]
+enum Target {
+    Local(u8),
+    Upvalue(u8),
+    Global,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    /// A class's `init` method, which always returns `this`.
+    Initializer,
+    /// Returns its argument, so assigning through a setter yields the value.
+    Setter,
+}
+
+/// Per-class compiler state, for `this` and `super`.
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Per-function compiler state. Nested function declarations push a new one.
+struct FunctionState {
+    /// `None` for the top-level script, which is compiled into `Compiler::chunk`.
+    function: Option<Function>,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    /// Declared parameter types, in order.
+    param_types: Vec<Type>,
+    return_type: Type,
+    /// The end offset of the last call or match expression, with the
+    /// `OpCall`s whose result becomes its value. A `return` of that
+    /// expression turns them into tail calls.
+    tail_calls: Option<(usize, Vec<usize>)>,
+    /// Values the expression being compiled keeps on the stack while it
+    /// compiles an operand, like the left operand of a binary operator.
+    temporaries: usize,
+}
+
+impl FunctionState {
+    /// The stack slot of the local at `index`.
+    fn slot(&self, index: usize) -> usize {
+        let uninitialized = self.locals[..index]
+            .iter()
+            .filter(|local| local.depth.is_none())
+            .count();
+        index - uninitialized + self.locals[index].temporaries
+    }
+
+    fn new(function: Option<Function>, kind: FunctionKind) -> Self {
+        let mut locals = Vec::new();
+        if function.is_some() {
+            // Slot zero holds the function being called, or the receiver in
+            // methods.
+            let name = match kind {
+                FunctionKind::Method | FunctionKind::Initializer | FunctionKind::Setter => {
+                    b"this".to_vec()
+                }
+                FunctionKind::Script | FunctionKind::Function => Vec::new(),
+            };
+            locals.push(Local {
+                name,
+                depth: Some(0),
+                binding: Binding::Var,
+                ty: TypeExpr::any(),
+                captured: false,
+                temporaries: 0,
+            });
+        }
+        FunctionState {
+            function,
+            kind,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            param_types: Vec::new(),
+            return_type: Type::Any,
+            tail_calls: None,
+            temporaries: 0,
+        }
+    }
+}
+
+/// Settings that change what the compiler emits.
+#[derive(Debug, Copy, Clone)]
+pub struct CompileOptions {
+    /// Compile `assert` statements. Without them, asserts are parsed and
+    /// checked but emit no code, so their expressions are never evaluated.
+    pub asserts: bool,
+    /// Evaluate operators on literal operands at compile time. Turning it off
+    /// keeps every operator in the bytecode, which is easier to follow when
+    /// debugging.
+    pub fold_constants: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            asserts: true,
+            fold_constants: true,
+        }
+    }
+}
+
 pub struct Compiler<'a, 'b> {
     parser: Parser<'a>,
+    options: CompileOptions,
     chunk: &'b mut Chunk,
+    globals: &'b mut GlobalBindings,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    /// Type of the expression compiled last, for the checks recorded on it.
+    expr_type: TypeExpr,
+    /// Type checks to run once the whole source has been parsed.
+    checks: Vec<Check>,
+    /// Offset where the left operand of the infix rule being compiled starts.
+    operand_start: usize,
 }
 
 impl<'a, 'b> Compiler<'a, 'b> {
-    pub fn new(source: &'a [u8], chunk: &'b mut Chunk) -> Self {
+    pub(crate) fn new(
+        source: &'a [u8],
+        chunk: &'b mut Chunk,
+        globals: &'b mut GlobalBindings,
+        options: CompileOptions,
+    ) -> Self {
         let scanner = Scanner::new(source);
         Compiler {
             parser: Parser::new(scanner),
+            options,
             chunk,
+            globals,
+            states: vec![FunctionState::new(None, FunctionKind::Script)],
+            classes: Vec::new(),
+            expr_type: TypeExpr::any(),
+            checks: Vec::new(),
+            operand_start: 0,
         }
     }
 
-    pub fn compile(&mut self) -> Result<bool> {
-        self.parser.had_error = false;
+    /// Compiles the whole source, failing with the first error reported.
+    pub fn compile(&mut self) -> Result<()> {
+        self.parser.first_error = None;
         self.parser.panic_mode = false;
 
         self.parser.advance()?;
-        expression(self)?;
-        self.parser
-            .consume(TokenType::Eof, "Expect end of expression")?;
+        while !self.parser.match_token(TokenType::Eof)? {
+            declaration(self)?;
+        }
         self.end()?;
 
-        Ok(!self.parser.had_error)
+        let checks = std::mem::take(&mut self.checks);
+        if !self.parser.had_error() {
+            for (line, msg) in checker::check(&checks, self.globals) {
+                self.parser.type_error(line, &msg);
+            }
+        }
+
+        match self.parser.first_error.take() {
+            Some((line, msg)) => error::CompileError { line, msg }.fail(),
+            None => Ok(()),
+        }
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().expect("no function state")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("no function state")
+    }
+
+    /// The calls whose result is the value of the expression just compiled.
+    fn tail_calls(&mut self) -> Vec<usize> {
+        let end = self.current_chunk().len();
+        match self.state_mut().tail_calls.take() {
+            Some((offset, calls)) if offset == end => calls,
+            _ => Vec::new(),
+        }
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        match self
+            .states
+            .last_mut()
+            .and_then(|state| state.function.as_mut())
+        {
+            Some(function) => &mut function.chunk,
+            None => self.chunk,
+        }
     }
 
     fn emit_byte(&mut self, byte: u8) -> Result<()> {
-        self.chunk.write(byte, self.parser.line() as u32);
+        let line = self.parser.line() as u32;
+        self.current_chunk().write(byte, line);
         Ok(())
     }
 
@@ -261,13 +678,18 @@ impl<'a, 'b> Compiler<'a, 'b> {
     }
 
     fn emit_return(&mut self) -> Result<()> {
+        match self.state().kind {
+            FunctionKind::Initializer => self.emit_bytes(OpGetLocal as u8, 0)?,
+            FunctionKind::Setter => self.emit_bytes(OpGetLocal as u8, 1)?,
+            _ => self.emit_byte(OpNil as u8)?,
+        }
         self.emit_byte(OpReturn as u8)
     }
 
     fn end(&mut self) -> Result<()> {
         self.emit_return()?;
 
-        if cfg!(feature = "debug-print-code") && !self.parser.had_error {
+        if cfg!(feature = "debug-print-code") && !self.parser.had_error() {
             disassemble(self.chunk, "code");
         }
         Ok(())
@@ -275,98 +697,1975 @@ impl<'a, 'b> Compiler<'a, 'b> {
 
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<()> {
         self.parser.advance()?;
-        let prefix_rule = get_rule(self.parser.previous()?.ty).prefix()?;
-        prefix_rule(self)?;
+        let prefix_rule = match get_rule(self.parser.previous()?.ty).prefix() {
+            Ok(rule) => rule,
+            Err(_) => return self.parser.error("Expect expression."),
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        self.expr_type = TypeExpr::any();
+        let start = self.current_chunk().len();
+        prefix_rule(self, can_assign)?;
+        self.parse_infix(start, precedence, can_assign)
+    }
 
+    /// The infix half of `parse_precedence`, for callers that compiled the
+    /// prefix expression themselves, starting at offset `start`.
+    fn parse_infix(
+        &mut self,
+        start: usize,
+        precedence: Precedence,
+        can_assign: bool,
+    ) -> Result<()> {
         while precedence <= get_rule(self.parser.current()?.ty).precedence {
             self.parser.advance()?;
             let infix_rule = get_rule(self.parser.previous()?.ty).infix()?;
-            infix_rule(self)?;
+            // Infix rules find the left operand's type in `expr_type` and
+            // replace it with the result's, and where its code starts in
+            // `operand_start`.
+            self.operand_start = start;
+            infix_rule(self, can_assign)?;
+        }
+
+        if can_assign && self.parser.match_token(TokenType::Equal)? {
+            self.parser.error("Invalid assignment target.")?;
         }
         Ok(())
     }
 
     fn emit_constant(&mut self, value: Value) -> Result<()> {
-        self.chunk
-            .write_constant(value, self.parser.previous()?.line as u32);
+        let line = self.parser.previous()?.line as u32;
+        self.current_chunk().write_constant(value, line);
+        Ok(())
+    }
+
+    fn emit_indexed(&mut self, op: OpCode, long_op: OpCode, value: Value) -> Result<()> {
+        let line = self.parser.previous()?.line as u32;
+        self.current_chunk().write_indexed(op, long_op, value, line);
+        Ok(())
+    }
+
+    /// The literal loaded by the code since `start`, if constant folding is on.
+    fn foldable_literal(&mut self, start: usize) -> Option<Value> {
+        if !self.options.fold_constants {
+            return None;
+        }
+        self.literal_since(start)
+    }
+
+    /// Replaces the literal loads emitted since `start` with a load of `value`,
+    /// dropping the constants they added to the table.
+    fn replace_literals(&mut self, start: usize, value: Value) -> Result<()> {
+        let chunk = self.current_chunk();
+        let mut first_constant = chunk.constants.len();
+        let mut offset = start;
+        while offset < chunk.code.len() {
+            let code = &chunk.code[offset..];
+            offset += match code[0].try_into() {
+                Ok(OpConstant) => {
+                    first_constant = first_constant.min(code[1] as usize);
+                    2
+                }
+                Ok(OpConstantLong) => {
+                    let index = read_u24(&[0, code[1], code[2], code[3]]) as usize;
+                    first_constant = first_constant.min(index);
+                    4
+                }
+                _ => 1,
+            };
+        }
+        chunk.truncate(start);
+        chunk.constants.truncate(first_constant);
+        self.emit_constant(value)
+    }
+
+    /// If the code emitted since `start` is a single literal load, returns the
+    /// value it loads.
+    fn literal_since(&mut self, start: usize) -> Option<Value> {
+        let chunk = self.current_chunk();
+        let code = &chunk.code[start..];
+        let op: OpCode = (*code.first()?).try_into().ok()?;
+        match (op, code.len()) {
+            (OpNil, 1) => Some(Value::Nil),
+            (OpTrue, 1) => Some(true.into()),
+            (OpFalse, 1) => Some(false.into()),
+            (OpConstant, 2) => Some(chunk.constants[code[1] as usize].to_value()),
+            (OpConstantLong, 4) => {
+                let index = read_u24(&[0, code[1], code[2], code[3]]);
+                Some(chunk.constants[index as usize].to_value())
+            }
+            _ => None,
+        }
+    }
+
+    /// Emits a two byte jump offset to be filled in by `patch_jump`, returning
+    /// its position.
+    fn emit_jump_operand(&mut self) -> Result<usize> {
+        self.emit_bytes(0xff, 0xff)?;
+        Ok(self.current_chunk().len() - 2)
+    }
+
+    /// Emits a backward jump to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) -> Result<()> {
+        self.emit_byte(OpLoop as u8)?;
+        let offset = self.current_chunk().len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.parser.error("Loop body too large.")?;
+        }
+        self.emit_bytes((offset >> 8) as u8, offset as u8)
+    }
+
+    /// Points the jump operand at `offset` to the next instruction emitted.
+    fn patch_jump(&mut self, offset: usize) -> Result<()> {
+        let jump = self.current_chunk().len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.parser.error("Too much code to jump over.")?;
+        }
+        let chunk = self.current_chunk();
+        chunk.code[offset] = (jump >> 8) as u8;
+        chunk.code[offset + 1] = jump as u8;
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) -> Result<()> {
+        self.state_mut().scope_depth -= 1;
+
+        let scope_depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_some_and(|depth| depth <= scope_depth) {
+                break;
+            }
+            if local.captured {
+                self.emit_byte(OpCloseUpvalue as u8)?;
+            } else {
+                self.emit_byte(OpPop as u8)?;
+            }
+            self.state_mut().locals.pop();
+        }
+        Ok(())
+    }
+
+    /// Starts compiling a nested function. Its code goes to its own chunk
+    /// until the matching `end_function`.
+    fn begin_function(&mut self, name: Option<String>, kind: FunctionKind) {
+        self.states
+            .push(FunctionState::new(Some(Function::new(name)), kind));
+        self.begin_scope();
+    }
+
+    /// Finishes the innermost function and emits the closure that creates it
+    /// in the enclosing function.
+    fn end_function(&mut self) -> Result<()> {
+        self.emit_return()?;
+        let state = self.states.pop().expect("no function state");
+        let mut function = state.function.expect("not compiling a function");
+        function.upvalue_count = state.upvalues.len();
+
+        if cfg!(feature = "debug-print-code") && !self.parser.had_error() {
+            disassemble(&function.chunk, &function.to_string());
+        }
+
+        let signature = Signature {
+            name: function.name.clone(),
+            params: function
+                .params
+                .iter()
+                .cloned()
+                .zip(state.param_types)
+                .collect(),
+            ret: state.return_type,
+        };
+
+        self.emit_indexed(OpClosure, OpClosureLong, Rc::new(function).into())?;
+        for upvalue in state.upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index)?;
+        }
+        self.expr_type = TypeExpr::inferred(Type::Function(Some(Rc::new(signature))));
+        Ok(())
+    }
+
+    /// Declares the variable named by the previous token, returning its name.
+    fn declare_variable(&mut self, binding: Binding) -> Result<Vec<u8>> {
+        let name = self.parser.previous()?.lexeme.clone();
+
+        if self.state().scope_depth == 0 {
+            match (
+                self.globals.vars.get(&name).map(|global| &global.binding),
+                &binding,
+            ) {
+                (Some(Binding::Const(_)), _) | (Some(Binding::Var), Binding::Const(_)) => {
+                    self.parser
+                        .error("Already a variable with this name in this scope.")?;
+                }
+                _ => {}
+            }
+            return Ok(name);
+        }
+
+        let scope_depth = self.state().scope_depth;
+        let duplicate = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+        if duplicate {
+            self.parser
+                .error("Already a variable with this name in this scope.")?;
+        }
+
+        if self.state().locals.len() > u8::MAX as usize {
+            self.parser.error("Too many local variables in function.")?;
+            return Ok(name);
+        }
+        self.state_mut().locals.push(Local {
+            name: name.clone(),
+            depth: None,
+            binding,
+            ty: TypeExpr::any(),
+            captured: false,
+            temporaries: 0,
+        });
+        Ok(name)
+    }
+
+    /// Adds an initialized local for a value already on the stack, returning
+    /// its slot. Hidden locals have an empty name.
+    fn add_local(&mut self, name: Vec<u8>) -> Result<u8> {
+        if self.state().locals.len() > u8::MAX as usize {
+            self.parser.error("Too many local variables in function.")?;
+            return Ok(0);
+        }
+        let scope_depth = self.state().scope_depth;
+        let temporaries = self.state().temporaries;
+        self.state_mut().locals.push(Local {
+            name,
+            depth: Some(scope_depth),
+            binding: Binding::Var,
+            ty: TypeExpr::any(),
+            captured: false,
+            temporaries,
+        });
+        let slot = self.state().slot(self.state().locals.len() - 1);
+        if slot > u8::MAX as usize {
+            self.parser.error("Too many local variables in function.")?;
+            return Ok(0);
+        }
+        Ok(slot as u8)
+    }
+
+    /// Compiles with `count` more values kept on the stack under what `compile`
+    /// pushes, so that the locals of a `match` in it get the right slots.
+    fn with_temporaries<T>(
+        &mut self,
+        count: usize,
+        compile: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.state_mut().temporaries += count;
+        let result = compile(self);
+        self.state_mut().temporaries -= count;
+        result
+    }
+
+    /// Marks a declared variable as initialized, recording what is known about
+    /// its value and its declared type. Globals are also stored at runtime.
+    fn define_variable(&mut self, name: Vec<u8>, binding: Binding, ty: TypeExpr) -> Result<()> {
+        if self.state().scope_depth > 0 {
+            let scope_depth = self.state().scope_depth;
+            if let Some(local) = self.state_mut().locals.last_mut() {
+                local.depth = Some(scope_depth);
+                local.binding = binding;
+                local.ty = ty;
+            }
+            return Ok(());
+        }
+
+        let value = String::from_utf8_lossy(&name).to_string().into();
+        self.globals.vars.insert(name, Global { binding, ty });
+        self.emit_indexed(OpDefineGlobal, OpDefineGlobalLong, value)
+    }
+
+    /// Defines variables declared together, whose values are on the stack in
+    /// declaration order.
+    fn define_variables(&mut self, names: Vec<Vec<u8>>) -> Result<()> {
+        if self.state().scope_depth > 0 {
+            let scope_depth = self.state().scope_depth;
+            let locals = &mut self.state_mut().locals;
+            let first = locals.len().saturating_sub(names.len());
+            for local in &mut locals[first..] {
+                local.depth = Some(scope_depth);
+            }
+            return Ok(());
+        }
+        for name in names.into_iter().rev() {
+            self.define_variable(name, Binding::Var, TypeExpr::any())?;
+        }
+        Ok(())
+    }
+
+    /// Emits the u24 constant indices of `names`, the operands of
+    /// instructions taking a list of names.
+    fn emit_names(&mut self, names: Vec<Vec<u8>>) -> Result<()> {
+        for name in names {
+            let index = self
+                .current_chunk()
+                .add_constant(String::from_utf8_lossy(&name).to_string().into());
+            for byte in write_u24(index as u32) {
+                self.emit_byte(byte)?;
+            }
+        }
         Ok(())
     }
+
+    /// Resolves `name` as a local of `state`, returning its index in `locals`
+    /// and its binding.
+    fn resolve_local(&mut self, state: usize, name: &[u8]) -> Result<Option<(usize, Binding)>> {
+        let found = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(index, local)| (index, local.depth, local.binding.clone()));
+
+        match found {
+            Some((_, None, _)) => {
+                self.parser
+                    .error("Can't read local variable in its own initializer.")?;
+                Ok(None)
+            }
+            Some((index, Some(_), binding)) => Ok(Some((index, binding))),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolves `name` as a variable of a function enclosing `state`, adding
+    /// upvalues to every function in between.
+    fn resolve_upvalue(&mut self, state: usize, name: &[u8]) -> Result<Option<(u8, Binding)>> {
+        if state == 0 {
+            return Ok(None);
+        }
+
+        if let Some((local, binding)) = self.resolve_local(state - 1, name)? {
+            self.states[state - 1].locals[local].captured = true;
+            let slot = self.states[state - 1].slot(local) as u8;
+            let index = self.add_upvalue(state, slot, true)?;
+            return Ok(Some((index, binding)));
+        }
+
+        if let Some((upvalue, binding)) = self.resolve_upvalue(state - 1, name)? {
+            let index = self.add_upvalue(state, upvalue, false)?;
+            return Ok(Some((index, binding)));
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> Result<u8> {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &self.states[state].upvalues;
+        if let Some(i) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(i as u8);
+        }
+        if upvalues.len() > u8::MAX as usize {
+            self.parser
+                .error("Too many closure variables in function.")?;
+            return Ok(0);
+        }
+        self.states[state].upvalues.push(upvalue);
+        Ok((self.states[state].upvalues.len() - 1) as u8)
+    }
+
+    fn resolve_variable(&mut self, name: &[u8]) -> Result<(Target, Option<Binding>)> {
+        let state = self.states.len() - 1;
+        if let Some((local, binding)) = self.resolve_local(state, name)? {
+            let slot = self.states[state].slot(local) as u8;
+            return Ok((Target::Local(slot), Some(binding)));
+        }
+        if let Some((index, binding)) = self.resolve_upvalue(state, name)? {
+            return Ok((Target::Upvalue(index), Some(binding)));
+        }
+        Ok((
+            Target::Global,
+            self.globals
+                .vars
+                .get(name)
+                .map(|global| global.binding.clone()),
+        ))
+    }
+
+    /// The binding and declared type of the variable `name` refers to, looked
+    /// up without capturing it. `None` for a local whose initializer is still
+    /// being compiled, which `resolve_variable` reports.
+    fn lookup(&self, name: &[u8]) -> Option<(&Binding, &TypeExpr)> {
+        let local = self
+            .states
+            .iter()
+            .rev()
+            .find_map(|state| state.locals.iter().rev().find(|local| local.name == name));
+        match local {
+            Some(local) if local.depth.is_none() => None,
+            Some(local) => Some((&local.binding, &local.ty)),
+            None => self
+                .globals
+                .vars
+                .get(name)
+                .map(|global| (&global.binding, &global.ty)),
+        }
+    }
+
+    /// The value of a constant whose initializer was a literal.
+    fn inlined_constant(&self, name: &[u8]) -> Option<Value> {
+        match self.lookup(name) {
+            Some((Binding::Const(Some(value)), _)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// The type of the variable `name`. A name not declared yet may be a
+    /// global declared further down.
+    fn variable_type(&self, name: &[u8]) -> TypeExpr {
+        match self.lookup(name) {
+            Some((_, ty)) => ty.clone(),
+            None => TypeExpr::Global(String::from_utf8_lossy(name).to_string()),
+        }
+    }
+
+    /// Records a check on the type of the expression compiled last.
+    fn check(&mut self, kind: CheckKind) {
+        let line = self.parser.line();
+        self.checks.push(Check { line, kind });
+    }
+
+    /// Records a check that the expression compiled last can be stored where
+    /// `expected` is declared. `what` names the place.
+    fn check_store(&mut self, expected: TypeExpr, what: String) {
+        let value = self.expr_type.clone();
+        self.check(CheckKind::Store {
+            what,
+            expected,
+            value,
+        });
+    }
+
+    /// Resolves an assignment target. Assigning to a constant is reported
+    /// here, at the target. A global declared further on is checked once the
+    /// program has been parsed.
+    fn resolve_assignment(&mut self, name: &[u8]) -> Result<Target> {
+        let (target, binding) = self.resolve_variable(name)?;
+        match binding {
+            Some(Binding::Const(_)) => self.parser.error(&format!(
+                "Can't assign to constant '{}'.",
+                String::from_utf8_lossy(name)
+            ))?,
+            Some(Binding::Var) => {}
+            None => self.check(CheckKind::Assign(String::from_utf8_lossy(name).to_string())),
+        }
+        Ok(target)
+    }
+
+    fn emit_set_variable(&mut self, name: &[u8], target: Target) -> Result<()> {
+        match target {
+            Target::Local(slot) => self.emit_bytes(OpSetLocal as u8, slot),
+            Target::Upvalue(index) => self.emit_bytes(OpSetUpvalue as u8, index),
+            Target::Global => self.emit_indexed(
+                OpSetGlobal,
+                OpSetGlobalLong,
+                String::from_utf8_lossy(name).to_string().into(),
+            ),
+        }
+    }
+
+    fn named_variable(&mut self, name: Vec<u8>, can_assign: bool) -> Result<()> {
+        if can_assign && self.parser.check(TokenType::Equal)? {
+            let target = self.resolve_assignment(&name)?;
+            self.parser.advance()?;
+            expression(self)?;
+            let what = format!("Variable '{}'", String::from_utf8_lossy(&name));
+            self.check_store(self.variable_type(&name), what);
+            return self.emit_set_variable(&name, target);
+        }
+
+        self.expr_type = self.variable_type(&name);
+        if let Some(value) = self.inlined_constant(&name) {
+            return self.emit_constant(value);
+        }
+        match self.resolve_variable(&name)?.0 {
+            Target::Local(slot) => self.emit_bytes(OpGetLocal as u8, slot),
+            Target::Upvalue(index) => self.emit_bytes(OpGetUpvalue as u8, index),
+            Target::Global => self.emit_indexed(
+                OpGetGlobal,
+                OpGetGlobalLong,
+                String::from_utf8_lossy(&name).to_string().into(),
+            ),
+        }
+    }
 }
 
-fn expression(compiler: &mut Compiler) -> Result<()> {
-    compiler.parse_precedence(Precedence::Assignment)?;
+fn declaration(compiler: &mut Compiler) -> Result<()> {
+    if compiler.parser.match_token(TokenType::Var)? {
+        var_declaration(compiler)?;
+    } else if compiler.parser.check(TokenType::Fun)? {
+        fun_declaration(compiler)?;
+    } else if compiler.parser.match_token(TokenType::Const)? {
+        const_declaration(compiler)?;
+    } else if compiler.parser.check(TokenType::Let)?
+        && compiler.parser.peek_next() == Some(TokenType::Identifier)
+    {
+        compiler.parser.advance()?;
+        const_declaration(compiler)?;
+    } else if compiler.parser.match_token(TokenType::Class)? {
+        class_declaration(compiler)?;
+    } else if compiler.parser.check(TokenType::Trait)?
+        && compiler.parser.peek_next() == Some(TokenType::Identifier)
+    {
+        compiler.parser.advance()?;
+        trait_declaration(compiler)?;
+    } else if compiler.parser.check(TokenType::Enum)?
+        && compiler.parser.peek_next() == Some(TokenType::Identifier)
+    {
+        compiler.parser.advance()?;
+        enum_declaration(compiler)?;
+    } else {
+        statement(compiler)?;
+    }
+
+    if compiler.parser.panic_mode {
+        compiler.parser.synchronize()?;
+    }
     Ok(())
 }
 
-fn number(compiler: &mut Compiler) -> Result<()> {
-    let previous = compiler.parser.previous()?;
-    let lexeme = String::from_utf8_lossy(&previous.lexeme);
-    let value: Value = lexeme
-        .parse::<f64>()
-        .context(error::ParseFloatError {
-            msg: format!("parse to number: {}", lexeme),
-            line: previous.line,
-        })?
-        .into();
-    compiler.emit_constant(value)
+fn var_declaration(compiler: &mut Compiler) -> Result<()> {
+    if compiler.parser.match_token(TokenType::LeftBracket)? {
+        return list_destructuring(compiler);
+    }
+    if compiler.parser.match_token(TokenType::LeftBrace)? {
+        return field_destructuring(compiler);
+    }
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect variable name.")?;
+    let name = compiler.declare_variable(Binding::Var)?;
+    let ty = optional_annotation(compiler)?;
+
+    if compiler.parser.match_token(TokenType::Equal)? {
+        expression(compiler)?;
+        let what = format!("Variable '{}'", String::from_utf8_lossy(&name));
+        compiler.check_store(TypeExpr::annotated(ty.clone()), what);
+    } else {
+        compiler.emit_byte(OpNil as u8)?;
+    }
+    compiler.parser.consume(
+        TokenType::Semicolon,
+        "Expect ';' after variable declaration.",
+    )?;
+
+    compiler.define_variable(name, Binding::Var, TypeExpr::annotated(ty))
 }
 
-fn grouping(compiler: &mut Compiler) -> Result<()> {
-    expression(compiler)?;
+/// `var [a, b, ...rest] = list;`. Without `...rest` the list must have
+/// exactly as many elements as there are names, with it at least that many;
+/// `rest` gets a new list of the remaining elements.
+fn list_destructuring(compiler: &mut Compiler) -> Result<()> {
+    let mut names = Vec::new();
+    let mut has_rest = false;
+    while !compiler.parser.check(TokenType::RightBracket)? {
+        if has_rest {
+            compiler
+                .parser
+                .error_at_current("The '...' element must be the last one.")?;
+        }
+        has_rest = compiler.parser.match_token(TokenType::DotDotDot)?;
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect variable name.")?;
+        names.push(compiler.declare_variable(Binding::Var)?);
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
     compiler
         .parser
-        .consume(TokenType::RightParen, "Expect ')' after expression")
-}
+        .consume(TokenType::RightBracket, "Expect ']' after names.")?;
+    if names.len() > u8::MAX as usize {
+        compiler
+            .parser
+            .error("Can't unpack more than 255 variables.")?;
+    }
+    destructuring_initializer(compiler)?;
 
-fn unary(compiler: &mut Compiler) -> Result<()> {
-    let operator_type = compiler.parser.previous()?.ty;
-    compiler.parse_precedence(Precedence::Unary)?;
-    let code = match operator_type {
-        TokenType::Minus => OpNegate,
-        TokenType::Bang => OpNot,
-        _ => unreachable!(),
-    } as u8;
-    compiler.emit_byte(code)
+    let count = names.len() - has_rest as usize;
+    compiler.emit_byte(OpUnpackList as u8)?;
+    compiler.emit_bytes(count as u8, has_rest as u8)?;
+    compiler.define_variables(names)
 }
 
-fn binary(compiler: &mut Compiler) -> Result<()> {
-    let operator_type = compiler.parser.previous()?.ty;
-    let rule = get_rule(operator_type);
-    compiler.parse_precedence(
-        (rule.precedence as u8 + 1)
-            .try_into()
-            .expect("invalid precedence"),
+/// `var {x, y} = instance;`, binding each name to the field of that name.
+fn field_destructuring(compiler: &mut Compiler) -> Result<()> {
+    let mut names = Vec::new();
+    while !compiler.parser.check(TokenType::RightBrace)? {
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect field name.")?;
+        names.push(compiler.declare_variable(Binding::Var)?);
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBrace, "Expect '}' after field names.")?;
+    if names.len() > u8::MAX as usize {
+        compiler
+            .parser
+            .error("Can't unpack more than 255 variables.")?;
+    }
+    destructuring_initializer(compiler)?;
+
+    compiler.emit_bytes(OpUnpackFields as u8, names.len() as u8)?;
+    compiler.emit_names(names.clone())?;
+    compiler.define_variables(names)
+}
+
+/// The `= value;` every destructuring declaration ends with.
+fn destructuring_initializer(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::Equal, "Expect '=' after names to unpack.")?;
+    expression(compiler)?;
+    compiler.parser.consume(
+        TokenType::Semicolon,
+        "Expect ';' after variable declaration.",
+    )
+}
+
+fn fun_declaration(compiler: &mut Compiler) -> Result<()> {
+    compiler.parser.advance()?;
+    if !compiler.parser.check(TokenType::Identifier)? {
+        // An anonymous function expression used as a statement.
+        let start = compiler.current_chunk().len();
+        function_expression(compiler, false)?;
+        compiler.parse_infix(start, Precedence::Assignment, false)?;
+        compiler
+            .parser
+            .consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        return compiler.emit_byte(OpPop as u8);
+    }
+
+    compiler.parser.advance()?;
+    let name = compiler.declare_variable(Binding::Var)?;
+    // Mark it initialized right away so the body can refer to itself.
+    if compiler.state().scope_depth > 0 {
+        let scope_depth = compiler.state().scope_depth;
+        if let Some(local) = compiler.state_mut().locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+    function(
+        compiler,
+        Some(String::from_utf8_lossy(&name).to_string()),
+        FunctionKind::Function,
+    )?;
+    let ty = compiler.expr_type.clone();
+    compiler.define_variable(name, Binding::Var, ty)
+}
+
+/// A `: Type` annotation if there is one, `Type::Any` otherwise.
+fn optional_annotation(compiler: &mut Compiler) -> Result<Type> {
+    if !compiler.parser.match_token(TokenType::Colon)? {
+        return Ok(Type::Any);
+    }
+    if compiler.parser.match_token(TokenType::Nil)? {
+        return Ok(Type::Nil);
+    }
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect type name after ':'.")?;
+    let name = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme).to_string();
+    Ok(Type::from_name(&name))
+}
+
+/// Compiles a parameter list and block body, starting at the `(`.
+fn function(compiler: &mut Compiler, name: Option<String>, kind: FunctionKind) -> Result<()> {
+    compiler.begin_function(name, kind);
+    compiler
+        .parser
+        .consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+    parameters(compiler)?;
+    compiler
+        .parser
+        .consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+    compiler.state_mut().return_type = optional_annotation(compiler)?;
+    let arity = compiler.state().function.as_ref().map_or(0, |f| f.arity);
+    if kind == FunctionKind::Setter && arity != 1 {
+        compiler
+            .parser
+            .error("A setter must have exactly one parameter.")?;
+    }
+    compiler
+        .parser
+        .consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+    block(compiler)?;
+    compiler.end_function()
+}
+
+/// Compiles a parameter list. A parameter may have a type annotation and a
+/// default value, which is compiled into the function's prologue and
+/// evaluated when the call didn't pass that argument.
+fn parameters(compiler: &mut Compiler) -> Result<()> {
+    if compiler.parser.check(TokenType::RightParen)? {
+        return Ok(());
+    }
+    loop {
+        if compiler.parser.match_token(TokenType::DotDotDot)? {
+            return rest_parameter(compiler);
+        }
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect parameter name.")?;
+        let name = compiler.declare_variable(Binding::Var)?;
+        let param = String::from_utf8_lossy(&name).to_string();
+        let ty = optional_annotation(compiler)?;
+        compiler.state_mut().param_types.push(ty.clone());
+        compiler.define_variable(name, Binding::Var, TypeExpr::annotated(ty.clone()))?;
+
+        let function = compiler.state_mut().function.as_mut().expect("no function");
+        function.arity += 1;
+        function.params.push(param.clone());
+        let arity = function.arity;
+        let optional = function.required < arity - 1;
+        if arity > u8::MAX as usize {
+            compiler
+                .parser
+                .error("Can't have more than 255 parameters.")?;
+        }
+
+        if compiler.parser.match_token(TokenType::Equal)? {
+            let slot = arity as u8;
+            compiler.emit_bytes(OpSkipIfArg as u8, slot)?;
+            let jump = compiler.emit_jump_operand()?;
+            expression(compiler)?;
+            let what = format!("Default value of '{}'", param);
+            compiler.check_store(TypeExpr::annotated(ty), what);
+            compiler.emit_bytes(OpSetLocal as u8, slot)?;
+            compiler.emit_byte(OpPop as u8)?;
+            compiler.patch_jump(jump)?;
+        } else if optional {
+            compiler
+                .parser
+                .error("Parameter without a default can't follow one with a default.")?;
+        } else if let Some(function) = compiler.state_mut().function.as_mut() {
+            function.required += 1;
+        }
+
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            return Ok(());
+        }
+    }
+}
+
+/// `...rest`, which collects the arguments after the other parameters into a
+/// list. It has to be the last parameter.
+fn rest_parameter(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect parameter name after '...'.")?;
+    let name = compiler.declare_variable(Binding::Var)?;
+    compiler.define_variable(name, Binding::Var, TypeExpr::any())?;
+    if let Some(function) = compiler.state_mut().function.as_mut() {
+        function.has_rest = true;
+    }
+    if compiler.parser.check(TokenType::Comma)? {
+        compiler
+            .parser
+            .error_at_current("The '...' parameter must be the last one.")?;
+    }
+    Ok(())
+}
+
+fn class_declaration(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect class name.")?;
+    let class_name = compiler.parser.previous()?.lexeme.clone();
+    let name = compiler.declare_variable(Binding::Var)?;
+    compiler.emit_indexed(
+        OpClass,
+        OpClassLong,
+        String::from_utf8_lossy(&class_name).to_string().into(),
+    )?;
+    let class = String::from_utf8_lossy(&class_name).to_string();
+    let ty = TypeExpr::inferred(Type::ClassObject(class.clone()));
+    compiler.define_variable(name, Binding::Var, ty)?;
+    compiler.globals.classes.insert(class.clone(), None);
+    compiler.classes.push(ClassState {
+        has_superclass: false,
+    });
+
+    if compiler.parser.match_token(TokenType::Less)? {
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect superclass name.")?;
+        variable(compiler, false)?;
+        let superclass = compiler.parser.previous()?.lexeme.clone();
+        if superclass == class_name {
+            compiler
+                .parser
+                .error("A class can't inherit from itself.")?;
+        } else {
+            let superclass = String::from_utf8_lossy(&superclass).to_string();
+            compiler.globals.classes.insert(class, Some(superclass));
+        }
+
+        // The superclass lives in a local named `super` that methods capture.
+        compiler.begin_scope();
+        compiler.add_local(b"super".to_vec())?;
+        compiler.named_variable(class_name.clone(), false)?;
+        compiler.emit_byte(OpInherit as u8)?;
+        if let Some(class) = compiler.classes.last_mut() {
+            class.has_superclass = true;
+        }
+    }
+
+    // Trait methods override inherited ones; the class body overrides both.
+    if compiler.parser.match_token(TokenType::With)? {
+        compiler.named_variable(class_name.clone(), false)?;
+        let mut count = 0;
+        loop {
+            compiler
+                .parser
+                .consume(TokenType::Identifier, "Expect trait name.")?;
+            variable(compiler, false)?;
+            count += 1;
+            if count > u8::MAX as usize {
+                compiler.parser.error("Can't use more than 255 traits.")?;
+            }
+            if !compiler.parser.match_token(TokenType::Comma)? {
+                break;
+            }
+        }
+        compiler.emit_bytes(OpUseTraits as u8, count as u8)?;
+    }
+
+    compiler.named_variable(class_name, false)?;
+    class_body(compiler)?;
+
+    let class = compiler.classes.pop().expect("no class state");
+    if class.has_superclass {
+        compiler.end_scope()?;
+    }
+    Ok(())
+}
+
+/// `trait Name { methods }`, a set of methods for classes to include with
+/// `class Name with Trait`.
+fn trait_declaration(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect trait name.")?;
+    let trait_name = compiler.parser.previous()?.lexeme.clone();
+    let name = compiler.declare_variable(Binding::Var)?;
+    compiler.emit_indexed(
+        OpTrait,
+        OpTraitLong,
+        String::from_utf8_lossy(&trait_name).to_string().into(),
+    )?;
+    compiler.define_variable(name, Binding::Var, TypeExpr::any())?;
+    compiler.classes.push(ClassState {
+        has_superclass: false,
+    });
+
+    compiler.named_variable(trait_name, false)?;
+    class_body(compiler)?;
+    compiler.classes.pop();
+    Ok(())
+}
+
+/// Compiles the members between braces onto the class or trait on top of
+/// the stack, then pops it.
+fn class_body(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+    while !compiler.parser.check(TokenType::RightBrace)?
+        && !compiler.parser.check(TokenType::Eof)?
+    {
+        method(compiler)?;
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+    compiler.emit_byte(OpEndClass as u8)
+}
+
+/// A method, `static` method, `get name { ... }` getter or
+/// `set name(value) { ... }` setter.
+fn method(compiler: &mut Compiler) -> Result<()> {
+    let mut method_kind = MethodKind::Method;
+    if compiler
+        .parser
+        .peek_next()
+        .is_some_and(TokenType::is_identifier)
+    {
+        if compiler.parser.match_token(TokenType::Static)? {
+            method_kind = MethodKind::Static;
+        } else if compiler.parser.match_token(TokenType::Get)? {
+            method_kind = MethodKind::Getter;
+        } else if compiler.parser.match_token(TokenType::Set)? {
+            method_kind = MethodKind::Setter;
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect method name.")?;
+    let name = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme).to_string();
+
+    match method_kind {
+        MethodKind::Getter => {
+            compiler.begin_function(Some(name.clone()), FunctionKind::Method);
+            compiler
+                .parser
+                .consume(TokenType::LeftBrace, "Expect '{' before getter body.")?;
+            block(compiler)?;
+            compiler.end_function()?;
+        }
+        MethodKind::Setter => function(compiler, Some(name.clone()), FunctionKind::Setter)?,
+        MethodKind::Method if name == "init" => {
+            function(compiler, Some(name.clone()), FunctionKind::Initializer)?
+        }
+        MethodKind::Method | MethodKind::Static => {
+            function(compiler, Some(name.clone()), FunctionKind::Method)?
+        }
+    }
+    compiler.emit_indexed(OpMethod, OpMethodLong, name.into())?;
+    compiler.emit_byte(method_kind as u8)
+}
+
+/// `enum Name { A(x, y), B }`. The enum is known at compile time, so it is
+/// bound as a constant and patterns can refer to its variants.
+fn enum_declaration(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect enum name.")?;
+    let enum_name = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme).to_string();
+    let name = compiler.declare_variable(Binding::Const(None))?;
+    compiler
+        .parser
+        .consume(TokenType::LeftBrace, "Expect '{' before enum body.")?;
+
+    let mut variants: Vec<(String, Vec<String>)> = Vec::new();
+    while !compiler.parser.check(TokenType::RightBrace)? {
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect variant name.")?;
+        let variant = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme).to_string();
+        if variants.iter().any(|(other, _)| *other == variant) {
+            compiler
+                .parser
+                .error("Already a variant with this name in this enum.")?;
+        }
+        let mut fields = Vec::new();
+        if compiler.parser.match_token(TokenType::LeftParen)? {
+            loop {
+                compiler
+                    .parser
+                    .consume(TokenType::Identifier, "Expect field name.")?;
+                fields
+                    .push(String::from_utf8_lossy(&compiler.parser.previous()?.lexeme).to_string());
+                if !compiler.parser.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+            compiler
+                .parser
+                .consume(TokenType::RightParen, "Expect ')' after fields.")?;
+        }
+        variants.push((variant, fields));
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBrace, "Expect '}' after enum body.")?;
+
+    let value = Value::Enum(Rc::new(Enum {
+        name: enum_name,
+        variants,
+    }));
+    compiler.emit_constant(value.clone())?;
+    compiler.define_variable(name, Binding::Const(Some(value)), TypeExpr::any())
+}
+
+/// `const name = value;` or `let name = value;`, a variable that can't be
+/// assigned to. The two keywords mean the same.
+fn const_declaration(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect constant name.")?;
+    let name = compiler.declare_variable(Binding::Const(None))?;
+    let declared = optional_annotation(compiler)?;
+
+    compiler
+        .parser
+        .consume(TokenType::Equal, "Expect '=' after constant name.")?;
+    let start = compiler.current_chunk().len();
+    expression(compiler)?;
+    let what = format!("Constant '{}'", String::from_utf8_lossy(&name));
+    compiler.check_store(TypeExpr::annotated(declared.clone()), what);
+    // A constant keeps the type of its value unless declared otherwise.
+    let ty = match declared {
+        Type::Any => compiler.expr_type.clone(),
+        declared => TypeExpr::annotated(declared),
+    };
+    // A broken initializer may still leave a literal behind; don't inline it.
+    let value = if compiler.parser.panic_mode {
+        None
+    } else {
+        compiler.literal_since(start)
+    };
+    compiler.parser.consume(
+        TokenType::Semicolon,
+        "Expect ';' after constant declaration.",
     )?;
 
+    compiler.define_variable(name, Binding::Const(value), ty)
+}
+
+fn statement(compiler: &mut Compiler) -> Result<()> {
+    if compiler.parser.match_token(TokenType::Print)? {
+        print_statement(compiler)
+    } else if compiler.parser.match_token(TokenType::Return)? {
+        return_statement(compiler)
+    } else if compiler.parser.match_token(TokenType::Yield)? {
+        yield_statement(compiler)
+    } else if compiler.parser.match_token(TokenType::Assert)? {
+        assert_statement(compiler)
+    } else if compiler.parser.match_token(TokenType::For)? {
+        for_statement(compiler)
+    } else if compiler.parser.match_token(TokenType::LeftBrace)? {
+        compiler.begin_scope();
+        block(compiler)?;
+        compiler.end_scope()
+    } else {
+        expression_statement(compiler)
+    }
+}
+
+/// `for (x in iterable) body`. The iterable and the iteration state live in
+/// hidden locals; `OpForIter` advances them and pushes the next element, or
+/// jumps past the loop when there is none. Each iteration gets a fresh `x`.
+fn for_statement(compiler: &mut Compiler) -> Result<()> {
+    compiler
+        .parser
+        .consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+    compiler.parser.match_token(TokenType::Var)?;
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect loop variable name.")?;
+    let name = compiler.parser.previous()?.lexeme.clone();
+    compiler
+        .parser
+        .consume(TokenType::In, "Expect 'in' after loop variable.")?;
+
+    compiler.begin_scope();
+    expression(compiler)?;
+    let iterable = compiler.add_local(Vec::new())?;
+    compiler.emit_byte(OpNil as u8)?;
+    compiler.add_local(Vec::new())?;
+    compiler
+        .parser
+        .consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+    let loop_start = compiler.current_chunk().len();
+    compiler.emit_bytes(OpForIter as u8, iterable)?;
+    let exit = compiler.emit_jump_operand()?;
+
+    compiler.begin_scope();
+    compiler.add_local(name)?;
+    statement(compiler)?;
+    compiler.end_scope()?;
+
+    compiler.emit_loop(loop_start)?;
+    compiler.patch_jump(exit)?;
+    compiler.end_scope()
+}
+
+fn block(compiler: &mut Compiler) -> Result<()> {
+    while !compiler.parser.check(TokenType::RightBrace)?
+        && !compiler.parser.check(TokenType::Eof)?
+    {
+        declaration(compiler)?;
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBrace, "Expect '}' after block.")
+}
+
+fn print_statement(compiler: &mut Compiler) -> Result<()> {
+    expression(compiler)?;
+    compiler
+        .parser
+        .consume(TokenType::Semicolon, "Expect ';' after value.")?;
+    compiler.emit_byte(OpPrint as u8)
+}
+
+fn return_statement(compiler: &mut Compiler) -> Result<()> {
+    if compiler.state().function.is_none() {
+        compiler.parser.error("Can't return from top-level code.")?;
+    }
+
+    if compiler.parser.match_token(TokenType::Semicolon)? {
+        if compiler.state().kind == FunctionKind::Function {
+            compiler.expr_type = TypeExpr::inferred(Type::Nil);
+            check_return_type(compiler);
+        }
+        return compiler.emit_return();
+    }
+    match compiler.state().kind {
+        FunctionKind::Initializer => compiler
+            .parser
+            .error("Can't return a value from an initializer.")?,
+        FunctionKind::Setter => compiler
+            .parser
+            .error("Can't return a value from a setter.")?,
+        _ => {}
+    }
+    expression(compiler)?;
+    check_return_type(compiler);
+    compiler
+        .parser
+        .consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+    // A call ending the return value can reuse this function's frame. The
+    // `OpReturn` stays for jumps past the call and for callees that don't
+    // get a frame.
+    for offset in compiler.tail_calls() {
+        compiler.current_chunk().code[offset] = OpTailCall as u8;
+    }
+    compiler.emit_byte(OpReturn as u8)
+}
+
+fn check_return_type(compiler: &mut Compiler) {
+    let what = match compiler
+        .state()
+        .function
+        .as_ref()
+        .and_then(|f| f.name.as_ref())
+    {
+        Some(name) => format!("Return value of '{}'", name),
+        None => "Return value".to_string(),
+    };
+    let expected = compiler.state().return_type.clone();
+    compiler.check_store(TypeExpr::annotated(expected), what);
+}
+
+/// `yield value;` suspends the enclosing function, which makes it a generator.
+fn yield_statement(compiler: &mut Compiler) -> Result<()> {
+    if compiler.state().kind == FunctionKind::Initializer {
+        compiler.parser.error("Can't yield from an initializer.")?;
+    }
+    match compiler.state_mut().function.as_mut() {
+        Some(function) => function.is_generator = true,
+        None => compiler.parser.error("Can't yield from top-level code.")?,
+    }
+
+    if compiler.parser.match_token(TokenType::Semicolon)? {
+        compiler.emit_byte(OpNil as u8)?;
+    } else {
+        expression(compiler)?;
+        compiler
+            .parser
+            .consume(TokenType::Semicolon, "Expect ';' after yield value.")?;
+    }
+    compiler.emit_byte(OpYield as u8)
+}
+
+/// `assert condition;` or `assert condition, message;`. The message is only
+/// evaluated when the condition is false; the error shows the condition's
+/// source text.
+fn assert_statement(compiler: &mut Compiler) -> Result<()> {
+    let start = compiler.current_chunk().len();
+    let source_start = compiler.parser.current()?.start;
+    expression(compiler)?;
+    let source = String::from_utf8_lossy(compiler.parser.source_since(source_start)?).to_string();
+
+    compiler.emit_byte(OpJumpIfFalse as u8)?;
+    let fail = compiler.emit_jump_operand()?;
+    compiler.emit_byte(OpPop as u8)?;
+    compiler.emit_byte(OpJump as u8)?;
+    let end = compiler.emit_jump_operand()?;
+    compiler.patch_jump(fail)?;
+    compiler.emit_byte(OpPop as u8)?;
+    if compiler.parser.match_token(TokenType::Comma)? {
+        expression(compiler)?;
+    } else {
+        compiler.emit_byte(OpNil as u8)?;
+    }
+    compiler.emit_indexed(OpAssertFail, OpAssertFailLong, source.into())?;
+    compiler.patch_jump(end)?;
+    compiler
+        .parser
+        .consume(TokenType::Semicolon, "Expect ';' after assertion.")?;
+
+    if !compiler.options.asserts {
+        compiler.current_chunk().truncate(start);
+    }
+    Ok(())
+}
+
+fn expression_statement(compiler: &mut Compiler) -> Result<()> {
+    if compiler.parser.match_token(TokenType::Identifier)? {
+        let name = compiler.parser.previous()?.lexeme.clone();
+        if compiler.parser.check(TokenType::Comma)? {
+            return parallel_assignment(compiler, name);
+        }
+        let start = compiler.current_chunk().len();
+        compiler.named_variable(name, true)?;
+        compiler.parse_infix(start, Precedence::Assignment, true)?;
+    } else {
+        expression(compiler)?;
+    }
+    compiler
+        .parser
+        .consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+    compiler.emit_byte(OpPop as u8)
+}
+
+/// `a, b = b, a;`. Every value is evaluated before any target is assigned.
+fn parallel_assignment(compiler: &mut Compiler, first: Vec<u8>) -> Result<()> {
+    let target = compiler.resolve_assignment(&first)?;
+    let mut targets = vec![(first, target)];
+    while compiler.parser.match_token(TokenType::Comma)? {
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect variable name.")?;
+        let name = compiler.parser.previous()?.lexeme.clone();
+        let target = compiler.resolve_assignment(&name)?;
+        targets.push((name, target));
+    }
+    compiler
+        .parser
+        .consume(TokenType::Equal, "Expect '=' after assignment targets.")?;
+
+    let mut values = 0;
+    loop {
+        compiler.with_temporaries(values, expression)?;
+        if let Some((name, _)) = targets.get(values) {
+            let what = format!("Variable '{}'", String::from_utf8_lossy(name));
+            compiler.check_store(compiler.variable_type(name), what);
+        }
+        values += 1;
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
+    if values != targets.len() {
+        compiler.parser.error(&format!(
+            "Expect {} values to assign but got {}.",
+            targets.len(),
+            values
+        ))?;
+    }
+    compiler
+        .parser
+        .consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+
+    for (name, target) in targets.iter().rev() {
+        compiler.emit_set_variable(name, *target)?;
+        compiler.emit_byte(OpPop as u8)?;
+    }
+    Ok(())
+}
+
+fn expression(compiler: &mut Compiler) -> Result<()> {
+    compiler.parse_precedence(Precedence::Assignment)?;
+    Ok(())
+}
+
+fn number(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let value = number_value(compiler.parser.previous()?)?;
+    compiler.expr_type = TypeExpr::inferred(Type::Number);
+    compiler.emit_constant(value)
+}
+
+fn number_value(previous: &Token) -> Result<Value> {
+    let lexeme = String::from_utf8_lossy(&previous.lexeme).replace('_', "");
+    let radix = match lexeme.get(..2) {
+        Some("0x") | Some("0X") => Some(16),
+        Some("0o") | Some("0O") => Some(8),
+        Some("0b") | Some("0B") => Some(2),
+        _ => None,
+    };
+    let value: Value = if let Some(radix) = radix {
+        Value::from_bigint(BigInt::parse_bytes(&lexeme.as_bytes()[2..], radix).context(
+            error::ParseError {
+                msg: format!("parse to int: {}", lexeme),
+            },
+        )?)
+    } else if lexeme.contains(['.', 'e', 'E']) {
+        lexeme
+            .parse::<f64>()
+            .context(error::ParseFloatError {
+                msg: format!("parse to number: {}", lexeme),
+                line: previous.line,
+            })?
+            .into()
+    } else {
+        Value::from_bigint(lexeme.parse::<BigInt>().context(error::ParseIntError {
+            msg: format!("parse to int: {}", lexeme),
+            line: previous.line,
+        })?)
+    };
+    Ok(value)
+}
+
+fn grouping(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    if compiler.parser.at_arrow_params()? {
+        return arrow_function(compiler);
+    }
+    expression(compiler)?;
+    compiler
+        .parser
+        .consume(TokenType::RightParen, "Expect ')' after expression")
+}
+
+fn unary(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let operator_type = compiler.parser.previous()?.ty;
+    let start = compiler.current_chunk().len();
+    compiler.parse_precedence(Precedence::Unary)?;
+    let code = match operator_type {
+        TokenType::Minus => {
+            let operand = std::mem::replace(&mut compiler.expr_type, TypeExpr::any());
+            compiler.expr_type = TypeExpr::Negate(Box::new(operand));
+            compiler.check(CheckKind::Operands(compiler.expr_type.clone()));
+            OpNegate
+        }
+        TokenType::Bang => {
+            compiler.expr_type = TypeExpr::inferred(Type::Bool);
+            OpNot
+        }
+        _ => unreachable!(),
+    };
+    if let Some(operand) = compiler.foldable_literal(start) {
+        let folded = match code {
+            OpNegate => operand.negate().ok(),
+            _ => Some(operand.is_falsey().into()),
+        };
+        if let Some(value) = folded {
+            return compiler.replace_literals(start, value);
+        }
+    }
+    compiler.emit_byte(code as u8)
+}
+
+fn binary(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let operator = compiler.parser.previous()?;
+    let operator_type = operator.ty;
+    let symbol = String::from_utf8_lossy(&operator.lexeme).to_string();
+    let left = compiler.expr_type.clone();
+    let start = compiler.operand_start;
+    let left_value = compiler.foldable_literal(start);
+    let right_start = compiler.current_chunk().len();
+    let rule = get_rule(operator_type);
+    let precedence = (rule.precedence as u8 + 1)
+        .try_into()
+        .expect("invalid precedence");
+    compiler.with_temporaries(1, |compiler| compiler.parse_precedence(precedence))?;
+    let right = std::mem::replace(&mut compiler.expr_type, TypeExpr::any());
+    compiler.expr_type = TypeExpr::Binary(symbol, Box::new(left), Box::new(right));
+    compiler.check(CheckKind::Operands(compiler.expr_type.clone()));
+
+    let right_value = compiler.foldable_literal(right_start);
+    if let (Some(left), Some(right)) = (left_value, right_value) {
+        if let Some(value) = fold_binary(operator_type, &left, &right) {
+            return compiler.replace_literals(start, value);
+        }
+    }
+
     match operator_type {
         TokenType::Plus => compiler.emit_byte(OpAdd as u8),
         TokenType::Minus => compiler.emit_byte(OpSubtract as u8),
         TokenType::Star => compiler.emit_byte(OpMultiply as u8),
         TokenType::Slash => compiler.emit_byte(OpDivide as u8),
+        TokenType::Percent => compiler.emit_byte(OpModulo as u8),
         TokenType::BangEqual => compiler.emit_bytes(OpEqual as u8, OpNot as u8),
         TokenType::EqualEqual => compiler.emit_byte(OpEqual as u8),
         TokenType::Greater => compiler.emit_byte(OpGreater as u8),
         TokenType::GreaterEqual => compiler.emit_bytes(OpLess as u8, OpNot as u8),
         TokenType::Less => compiler.emit_byte(OpLess as u8),
         TokenType::LessEqual => compiler.emit_bytes(OpGreater as u8, OpNot as u8),
+        TokenType::DotDot => compiler.emit_byte(OpRange as u8),
+        TokenType::DotDotEqual => compiler.emit_byte(OpRangeInclusive as u8),
         _ => unreachable!(),
     }
 }
 
-fn literal(compiler: &mut Compiler) -> Result<()> {
+/// The value of `left operator right` when both are literals, computed as
+/// the VM would. `None` when the VM would report an error, or call an
+/// operator method, so the operation is left for runtime.
+fn fold_binary(operator: TokenType, left: &Value, right: &Value) -> Option<Value> {
+    let less = || matches!(left.compare_numbers(right), Ok(Some(Ordering::Less)));
+    let greater = || matches!(left.compare_numbers(right), Ok(Some(Ordering::Greater)));
+    let divides = || !(matches!(right, Value::Int(0)) && left.is_integer());
+    let value = match operator {
+        TokenType::Plus => match (left, right) {
+            (Value::Str(l), Value::Str(r)) => format!("{}{}", l, r).into(),
+            _ => {
+                Value::arithmetic(left, right, i64::checked_add, |l, r| l + r, |l, r| l + r).ok()?
+            }
+        },
+        TokenType::Minus => {
+            Value::arithmetic(left, right, i64::checked_sub, |l, r| l - r, |l, r| l - r).ok()?
+        }
+        TokenType::Star => {
+            Value::arithmetic(left, right, i64::checked_mul, |l, r| l * r, |l, r| l * r).ok()?
+        }
+        TokenType::Slash if divides() => Value::divide(left, right).ok()?,
+        TokenType::Percent if divides() => {
+            Value::arithmetic(left, right, i64::checked_rem, |l, r| l % r, |l, r| l % r).ok()?
+        }
+        TokenType::EqualEqual => (left == right).into(),
+        TokenType::BangEqual => (left != right).into(),
+        // Comparisons of non-numbers are runtime errors.
+        _ if left.compare_numbers(right).is_err() => return None,
+        TokenType::Greater => greater().into(),
+        TokenType::GreaterEqual => (!less()).into(),
+        TokenType::Less => less().into(),
+        TokenType::LessEqual => (!greater()).into(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn literal(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
     let operator_type = compiler.parser.previous()?.ty;
-    let code = match operator_type {
-        TokenType::False => OpFalse,
-        TokenType::True => OpTrue,
-        TokenType::Nil => OpNil,
+    let (code, ty) = match operator_type {
+        TokenType::False => (OpFalse, Type::Bool),
+        TokenType::True => (OpTrue, Type::Bool),
+        TokenType::Nil => (OpNil, Type::Nil),
         _ => unreachable!(),
-    } as u8;
+    };
+    compiler.expr_type = TypeExpr::inferred(ty);
+    let code = code as u8;
     compiler.emit_byte(code)
 }
 
-fn string(compiler: &mut Compiler) -> Result<()> {
+fn string(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
     let s = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme)
         .trim_matches('"')
         .to_string();
+    compiler.expr_type = TypeExpr::inferred(Type::String);
     compiler.emit_constant(s.into())
 }
+
+fn variable(compiler: &mut Compiler, can_assign: bool) -> Result<()> {
+    let name = compiler.parser.previous()?.lexeme.clone();
+    compiler.named_variable(name, can_assign)
+}
+
+/// Compiles an argument list up to the closing `)`. Positional arguments
+/// come first; `name: value` arguments after them are matched to parameters by
+/// name when called. Returns the positional count, the names and the type of
+/// every argument in order. The callee or receiver is on the stack under them.
+fn argument_list(compiler: &mut Compiler) -> Result<(u8, Vec<Vec<u8>>, Vec<TypeExpr>)> {
+    let mut positional = 0;
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    if !compiler.parser.check(TokenType::RightParen)? {
+        loop {
+            let below = 1 + positional + names.len();
+            if compiler.parser.check(TokenType::Identifier)?
+                && compiler.parser.peek_next() == Some(TokenType::Colon)
+            {
+                compiler.parser.advance()?;
+                names.push(compiler.parser.previous()?.lexeme.clone());
+                compiler.parser.advance()?;
+                compiler.with_temporaries(below, expression)?;
+            } else {
+                if !names.is_empty() {
+                    compiler
+                        .parser
+                        .error_at_current("Positional argument can't follow named arguments.")?;
+                }
+                compiler.with_temporaries(below, expression)?;
+                positional += 1;
+            }
+            types.push(compiler.expr_type.clone());
+            if positional + names.len() > u8::MAX as usize {
+                compiler
+                    .parser
+                    .error("Can't have more than 255 arguments.")?;
+            }
+            if !compiler.parser.match_token(TokenType::Comma)? {
+                break;
+            }
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+    Ok((positional as u8, names, types))
+}
+
+/// A call. Its arguments are checked against the callee's signature, when
+/// the checker can tell what it is.
+fn call(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let callee = std::mem::replace(&mut compiler.expr_type, TypeExpr::any());
+    let (positional, names, mut types) = argument_list(compiler)?;
+    let named = types
+        .split_off(positional as usize)
+        .into_iter()
+        .zip(&names)
+        .map(|(ty, name)| (String::from_utf8_lossy(name).to_string(), ty))
+        .collect();
+    compiler.check(CheckKind::Arguments {
+        callee: callee.clone(),
+        positional: types,
+        named,
+    });
+    compiler.expr_type = TypeExpr::Call(Box::new(callee));
+    if names.is_empty() {
+        let offset = compiler.current_chunk().len();
+        compiler.emit_bytes(OpCall as u8, positional)?;
+        compiler.state_mut().tail_calls = Some((offset + 2, vec![offset]));
+        return Ok(());
+    }
+    compiler.emit_byte(OpCallNamed as u8)?;
+    compiler.emit_bytes(positional, names.len() as u8)?;
+    compiler.emit_names(names)
+}
+
+/// `receiver.name`, `receiver.name = value` or `receiver.name(args)`.
+fn dot(compiler: &mut Compiler, can_assign: bool) -> Result<()> {
+    // `Fiber.yield()` names a method after a keyword.
+    if !compiler.parser.match_token(TokenType::Yield)? {
+        compiler
+            .parser
+            .consume(TokenType::Identifier, "Expect property name after '.'.")?;
+    }
+    let name: Value = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme)
+        .to_string()
+        .into();
+    compiler.expr_type = TypeExpr::any();
+
+    if compiler.parser.match_token(TokenType::LeftParen)? {
+        let (arg_count, names, _) = argument_list(compiler)?;
+        if !names.is_empty() {
+            compiler
+                .parser
+                .error("Named arguments can only be passed to functions.")?;
+        }
+        compiler.emit_indexed(OpInvoke, OpInvokeLong, name)?;
+        compiler.emit_byte(arg_count)
+    } else if can_assign && compiler.parser.match_token(TokenType::Equal)? {
+        compiler.with_temporaries(1, expression)?;
+        compiler.emit_indexed(OpSetProperty, OpSetPropertyLong, name)
+    } else {
+        compiler.emit_indexed(OpGetProperty, OpGetPropertyLong, name)
+    }
+}
+
+/// `receiver?.name` or `receiver?.name(args)`. A nil receiver skips the rest
+/// of the call chain, which evaluates to nil.
+fn optional_dot(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let start = compiler.operand_start;
+    compiler.emit_byte(OpJumpIfNil as u8)?;
+    let jump = compiler.emit_jump_operand()?;
+    dot(compiler, false)?;
+    compiler.parse_infix(start, Precedence::Call, false)?;
+    compiler.patch_jump(jump)?;
+    compiler.expr_type = TypeExpr::any();
+    Ok(())
+}
+
+/// `value ?? default`, evaluating `default` only when `value` is nil.
+fn coalesce(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let left = std::mem::replace(&mut compiler.expr_type, TypeExpr::any());
+    compiler.emit_byte(OpJumpIfNotNil as u8)?;
+    let jump = compiler.emit_jump_operand()?;
+    compiler.emit_byte(OpPop as u8)?;
+    compiler.parse_precedence(Precedence::Coalesce)?;
+    compiler.patch_jump(jump)?;
+    let right = std::mem::replace(&mut compiler.expr_type, TypeExpr::any());
+    compiler.expr_type = TypeExpr::Coalesce(Box::new(left), Box::new(right));
+    Ok(())
+}
+
+/// `receiver[index]`, which slices strings by a range and calls an instance's
+/// `__index` method, or `receiver[index] = value` on a list or map.
+fn index(compiler: &mut Compiler, can_assign: bool) -> Result<()> {
+    compiler.with_temporaries(1, expression)?;
+    compiler
+        .parser
+        .consume(TokenType::RightBracket, "Expect ']' after index.")?;
+    if can_assign && compiler.parser.match_token(TokenType::Equal)? {
+        compiler.with_temporaries(2, expression)?;
+        compiler.expr_type = TypeExpr::any();
+        return compiler.emit_byte(OpSetIndex as u8);
+    }
+    compiler.expr_type = TypeExpr::any();
+    compiler.emit_byte(OpIndex as u8)
+}
+
+/// `[a, b, c]`. A trailing comma is allowed.
+fn list(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let mut count = 0;
+    while !compiler.parser.check(TokenType::RightBracket)? {
+        compiler.with_temporaries(count, expression)?;
+        if count == u8::MAX as usize {
+            compiler
+                .parser
+                .error("Can't have more than 255 elements in a list literal.")?;
+        }
+        count += 1;
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+    compiler.expr_type = TypeExpr::any();
+    compiler.emit_bytes(OpBuildList as u8, count as u8)
+}
+
+/// `{key: value, ...}`. Keys are expressions that must evaluate to strings.
+/// A trailing comma is allowed.
+fn map(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    let mut count = 0;
+    while !compiler.parser.check(TokenType::RightBrace)? {
+        compiler.with_temporaries(2 * count, expression)?;
+        compiler
+            .parser
+            .consume(TokenType::Colon, "Expect ':' after map key.")?;
+        compiler.with_temporaries(2 * count + 1, expression)?;
+        if count == u8::MAX as usize {
+            compiler
+                .parser
+                .error("Can't have more than 255 entries in a map literal.")?;
+        }
+        count += 1;
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+    compiler.expr_type = TypeExpr::any();
+    compiler.emit_bytes(OpBuildMap as u8, count as u8)
+}
+
+fn this(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    if compiler.classes.is_empty() {
+        return compiler
+            .parser
+            .error("Can't use 'this' outside of a class.");
+    }
+    compiler.named_variable(b"this".to_vec(), false)
+}
+
+/// `super.name` or `super.name(args)`, looked up in the superclass and bound
+/// to `this`.
+fn super_(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    match compiler.classes.last() {
+        None => compiler
+            .parser
+            .error("Can't use 'super' outside of a class.")?,
+        Some(class) if !class.has_superclass => compiler
+            .parser
+            .error("Can't use 'super' in a class with no superclass.")?,
+        Some(_) => {}
+    }
+    compiler
+        .parser
+        .consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+    compiler
+        .parser
+        .consume(TokenType::Identifier, "Expect superclass method name.")?;
+    let name: Value = String::from_utf8_lossy(&compiler.parser.previous()?.lexeme)
+        .to_string()
+        .into();
+
+    compiler.named_variable(b"this".to_vec(), false)?;
+    if compiler.parser.match_token(TokenType::LeftParen)? {
+        let (arg_count, names, _) = argument_list(compiler)?;
+        if !names.is_empty() {
+            compiler
+                .parser
+                .error("Named arguments can only be passed to functions.")?;
+        }
+        compiler.named_variable(b"super".to_vec(), false)?;
+        compiler.emit_indexed(OpSuperInvoke, OpSuperInvokeLong, name)?;
+        compiler.emit_byte(arg_count)
+    } else {
+        compiler.named_variable(b"super".to_vec(), false)?;
+        compiler.emit_indexed(OpGetSuper, OpGetSuperLong, name)
+    }
+}
+
+/// `match value { pattern if guard => result, ... }`. The value goes in a
+/// hidden local that each arm's `OpMatch` tests, pushing what the pattern
+/// binds as locals for the guard and result or jumping to the next arm. The
+/// result replaces the value in the hidden local, which is left on the stack.
+/// Its slots count the temporaries of an enclosing expression under it.
+fn match_(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    compiler.begin_scope();
+    expression(compiler)?;
+    let slot = compiler.add_local(Vec::new())?;
+    compiler
+        .parser
+        .consume(TokenType::LeftBrace, "Expect '{' after match value.")?;
+
+    let mut arms = Vec::new();
+    let mut end_jumps = Vec::new();
+    let mut tail_calls = Vec::new();
+    while !compiler.parser.check(TokenType::RightBrace)? {
+        compiler.begin_scope();
+        let mut bindings = Vec::new();
+        let pattern = Rc::new(pattern(compiler, &mut bindings)?);
+        compiler.emit_bytes(OpMatch as u8, slot)?;
+        let index = compiler
+            .current_chunk()
+            .add_constant(Value::Pattern(pattern.clone()));
+        for byte in write_u24(index as u32) {
+            compiler.emit_byte(byte)?;
+        }
+        let next_arm = compiler.emit_jump_operand()?;
+        for name in bindings {
+            compiler.add_local(name)?;
+        }
+
+        let guard = if compiler.parser.match_token(TokenType::If)? {
+            expression(compiler)?;
+            compiler.emit_byte(OpJumpIfFalse as u8)?;
+            let jump = compiler.emit_jump_operand()?;
+            compiler.emit_byte(OpPop as u8)?;
+            Some(jump)
+        } else {
+            None
+        };
+        compiler
+            .parser
+            .consume(TokenType::Arrow, "Expect '=>' after match pattern.")?;
+        expression(compiler)?;
+        tail_calls.extend(compiler.tail_calls());
+        compiler.emit_bytes(OpSetLocal as u8, slot)?;
+        compiler.emit_byte(OpPop as u8)?;
+
+        let scope_depth = compiler.state().scope_depth;
+        let bound: Vec<bool> = compiler
+            .state()
+            .locals
+            .iter()
+            .filter(|local| local.depth == Some(scope_depth))
+            .map(|local| local.captured)
+            .collect();
+        compiler.end_scope()?;
+        compiler.emit_byte(OpJump as u8)?;
+        end_jumps.push(compiler.emit_jump_operand()?);
+
+        if let Some(jump) = guard {
+            // The guard failed: drop its result and the bindings.
+            compiler.patch_jump(jump)?;
+            compiler.emit_byte(OpPop as u8)?;
+            for captured in bound.into_iter().rev() {
+                let op = if captured { OpCloseUpvalue } else { OpPop };
+                compiler.emit_byte(op as u8)?;
+            }
+        }
+        compiler.patch_jump(next_arm)?;
+        arms.push((pattern, guard.is_some()));
+
+        if !compiler.parser.match_token(TokenType::Comma)? {
+            break;
+        }
+    }
+    compiler
+        .parser
+        .consume(TokenType::RightBrace, "Expect '}' after match arms.")?;
+    compiler.emit_bytes(OpNoMatch as u8, slot)?;
+    for jump in end_jumps {
+        compiler.patch_jump(jump)?;
+    }
+
+    let end = compiler.current_chunk().len();
+    let state = compiler.state_mut();
+    state.tail_calls = Some((end, tail_calls));
+    state.locals.pop();
+    state.scope_depth -= 1;
+    compiler.expr_type = TypeExpr::any();
+
+    if let Some(missing) = missing_variants(&arms) {
+        compiler.parser.warning(&missing)?;
+    }
+    Ok(())
+}
+
+/// Describes the variants a match over an enum doesn't cover, if any. Guarded
+/// arms and arms with refutable field patterns don't count as covering.
+fn missing_variants(arms: &[(Rc<Pattern>, bool)]) -> Option<String> {
+    let unguarded = || {
+        arms.iter()
+            .filter(|(_, guarded)| !guarded)
+            .map(|(pattern, _)| pattern)
+    };
+    if unguarded().any(|pattern| pattern.is_irrefutable()) {
+        return None;
+    }
+    let enum_ = arms.iter().find_map(|(pattern, _)| match &**pattern {
+        Pattern::Variant(enum_, _, _) => Some(enum_.clone()),
+        _ => None,
+    })?;
+
+    let mut covered = vec![false; enum_.variants.len()];
+    for pattern in unguarded() {
+        if let Pattern::Variant(other, tag, fields) = &**pattern {
+            if Rc::ptr_eq(other, &enum_) && fields.iter().all(Pattern::is_irrefutable) {
+                covered[*tag] = true;
+            }
+        }
+    }
+    let missing: Vec<&str> = enum_
+        .variants
+        .iter()
+        .zip(covered)
+        .filter(|(_, covered)| !covered)
+        .map(|((name, _), _)| name.as_str())
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "Match over {} is not exhaustive; missing {}.",
+        enum_.name,
+        missing.join(", ")
+    ))
+}
+
+/// Parses a `match` pattern, adding the names it binds to `bindings`.
+fn pattern(compiler: &mut Compiler, bindings: &mut Vec<Vec<u8>>) -> Result<Pattern> {
+    let parser = &mut compiler.parser;
+    if parser.match_token(TokenType::Minus)? {
+        parser.consume(TokenType::Number, "Expect number after '-' in pattern.")?;
+        let value = match number_value(parser.previous()?)? {
+            Value::Int(v) => v
+                .checked_neg()
+                .map_or_else(|| Value::from_bigint(-BigInt::from(v)), Value::Int),
+            Value::BigInt(v) => Value::from_bigint(-v),
+            Value::Number(v) => Value::Number(-v),
+            value => value,
+        };
+        return Ok(Pattern::Literal(value));
+    }
+    if parser.match_token(TokenType::Number)? {
+        return Ok(Pattern::Literal(number_value(parser.previous()?)?));
+    }
+    if parser.match_token(TokenType::Str)? {
+        let s = String::from_utf8_lossy(&parser.previous()?.lexeme)
+            .trim_matches('"')
+            .to_string();
+        return Ok(Pattern::Literal(s.into()));
+    }
+    if parser.match_token(TokenType::True)? {
+        return Ok(Pattern::Literal(true.into()));
+    }
+    if parser.match_token(TokenType::False)? {
+        return Ok(Pattern::Literal(false.into()));
+    }
+    if parser.match_token(TokenType::Nil)? {
+        return Ok(Pattern::Literal(Value::Nil));
+    }
+
+    parser.consume(TokenType::Identifier, "Expect pattern.")?;
+    let name = parser.previous()?.lexeme.clone();
+    if name == b"_" {
+        return Ok(Pattern::Wildcard);
+    }
+    if !parser.match_token(TokenType::Dot)? {
+        if bindings.contains(&name) {
+            parser.error("Already a variable with this name in this pattern.")?;
+        }
+        bindings.push(name.clone());
+        return Ok(Pattern::Bind(String::from_utf8_lossy(&name).to_string()));
+    }
+
+    let enum_ = match compiler.inlined_constant(&name) {
+        Some(Value::Enum(enum_)) => enum_,
+        _ => {
+            compiler
+                .parser
+                .error("Expect an enum before '.' in pattern.")?;
+            return Ok(Pattern::Wildcard);
+        }
+    };
+    let parser = &mut compiler.parser;
+    parser.consume(TokenType::Identifier, "Expect variant name after '.'.")?;
+    let variant = String::from_utf8_lossy(&parser.previous()?.lexeme).to_string();
+    let tag = match enum_.variant(&variant) {
+        Some(tag) => tag,
+        None => {
+            parser.error(&format!(
+                "Enum '{}' has no variant '{}'.",
+                enum_.name, variant
+            ))?;
+            return Ok(Pattern::Wildcard);
+        }
+    };
+
+    let mut fields = Vec::new();
+    if compiler.parser.match_token(TokenType::LeftParen)? {
+        loop {
+            fields.push(pattern(compiler, bindings)?);
+            if !compiler.parser.match_token(TokenType::Comma)? {
+                break;
+            }
+        }
+        compiler
+            .parser
+            .consume(TokenType::RightParen, "Expect ')' after field patterns.")?;
+    }
+    let arity = enum_.variants[tag].1.len();
+    if fields.len() != arity {
+        compiler.parser.error(&format!(
+            "Expect {} field patterns for {}.{} but got {}.",
+            arity,
+            enum_.name,
+            variant,
+            fields.len()
+        ))?;
+    }
+    Ok(Pattern::Variant(enum_, tag, fields))
+}
+
+/// `fun (a, b) { ... }` used as an expression.
+fn function_expression(compiler: &mut Compiler, _can_assign: bool) -> Result<()> {
+    function(compiler, None, FunctionKind::Function)
+}
+
+/// `(a, b) => expression` or `(a, b) => { statements }`, starting after the `(`.
+fn arrow_function(compiler: &mut Compiler) -> Result<()> {
+    compiler.begin_function(None, FunctionKind::Function);
+    parameters(compiler)?;
+    compiler
+        .parser
+        .consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+    compiler.state_mut().return_type = optional_annotation(compiler)?;
+    compiler
+        .parser
+        .consume(TokenType::Arrow, "Expect '=>' after parameters.")?;
+
+    if compiler.parser.match_token(TokenType::LeftBrace)? {
+        block(compiler)?;
+    } else {
+        expression(compiler)?;
+        check_return_type(compiler);
+        compiler.emit_byte(OpReturn as u8)?;
+    }
+    compiler.end_function()
+}
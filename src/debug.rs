@@ -1,7 +1,8 @@
 use std::convert::TryInto;
 
 use crate::chunk::{read_u24, Chunk, OpCode};
-use crate::value::Value;
+use crate::object::MethodKind;
+use crate::value::Slot;
 
 pub fn disassemble(chunk: &Chunk, name: &str) {
     println!("== {} ==", name);
@@ -23,10 +24,161 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
 
     let instruction: Result<OpCode, String> = chunk.code[offset].try_into();
     match instruction {
-        Ok(op @ OpCode::OpConstant) => constant_instruction(chunk, op.to_string().as_str(), offset),
-        Ok(op @ OpCode::OpConstantLong) => {
+        Ok(op @ OpCode::OpConstant)
+        | Ok(op @ OpCode::OpDefineGlobal)
+        | Ok(op @ OpCode::OpGetGlobal)
+        | Ok(op @ OpCode::OpSetGlobal)
+        | Ok(op @ OpCode::OpGetProperty)
+        | Ok(op @ OpCode::OpSetProperty)
+        | Ok(op @ OpCode::OpGetSuper)
+        | Ok(op @ OpCode::OpClass)
+        | Ok(op @ OpCode::OpTrait)
+        | Ok(op @ OpCode::OpAssertFail) => {
+            constant_instruction(chunk, op.to_string().as_str(), offset)
+        }
+        Ok(op @ OpCode::OpConstantLong)
+        | Ok(op @ OpCode::OpDefineGlobalLong)
+        | Ok(op @ OpCode::OpGetGlobalLong)
+        | Ok(op @ OpCode::OpSetGlobalLong)
+        | Ok(op @ OpCode::OpGetPropertyLong)
+        | Ok(op @ OpCode::OpSetPropertyLong)
+        | Ok(op @ OpCode::OpGetSuperLong)
+        | Ok(op @ OpCode::OpClassLong)
+        | Ok(op @ OpCode::OpTraitLong)
+        | Ok(op @ OpCode::OpAssertFailLong) => {
             constant_long_instruction(chunk, op.to_string().as_str(), offset)
         }
+        Ok(op @ OpCode::OpGetLocal)
+        | Ok(op @ OpCode::OpSetLocal)
+        | Ok(op @ OpCode::OpGetUpvalue)
+        | Ok(op @ OpCode::OpSetUpvalue)
+        | Ok(op @ OpCode::OpCall)
+        | Ok(op @ OpCode::OpTailCall)
+        | Ok(op @ OpCode::OpUseTraits)
+        | Ok(op @ OpCode::OpBuildList)
+        | Ok(op @ OpCode::OpBuildMap)
+        | Ok(op @ OpCode::OpNoMatch) => byte_instruction(chunk, op.to_string().as_str(), offset),
+        Ok(op @ OpCode::OpSkipIfArg) | Ok(op @ OpCode::OpForIter) => {
+            slot_jump_instruction(chunk, op.to_string().as_str(), offset)
+        }
+        Ok(op @ OpCode::OpJump)
+        | Ok(op @ OpCode::OpJumpIfFalse)
+        | Ok(op @ OpCode::OpJumpIfNil)
+        | Ok(op @ OpCode::OpJumpIfNotNil) => {
+            let jump = u16::from(chunk.code[offset + 1]) << 8 | u16::from(chunk.code[offset + 2]);
+            println!(
+                "{:>-16} {:4} -> {}",
+                op.to_string(),
+                offset,
+                offset + 3 + jump as usize
+            );
+            offset + 3
+        }
+        Ok(OpCode::OpMatch) => {
+            let slot = chunk.code[offset + 1];
+            let constant = read_u24(&[
+                0,
+                chunk.code[offset + 2],
+                chunk.code[offset + 3],
+                chunk.code[offset + 4],
+            ]);
+            let jump = u16::from(chunk.code[offset + 5]) << 8 | u16::from(chunk.code[offset + 6]);
+            println!(
+                "{:>-16} {:4} {} -> {}",
+                "OpMatch",
+                slot,
+                chunk.constants[constant as usize],
+                offset + 7 + jump as usize
+            );
+            offset + 7
+        }
+        Ok(OpCode::OpLoop) => {
+            let jump = u16::from(chunk.code[offset + 1]) << 8 | u16::from(chunk.code[offset + 2]);
+            println!(
+                "{:>-16} {:4} -> {}",
+                "OpLoop",
+                offset,
+                offset + 3 - jump as usize
+            );
+            offset + 3
+        }
+        Ok(OpCode::OpCallNamed) => {
+            let positional = chunk.code[offset + 1];
+            let named = chunk.code[offset + 2] as usize;
+            print!("{:>-16} {:4}", "OpCallNamed", positional);
+            for i in 0..named {
+                let at = offset + 3 + i * 3;
+                let constant =
+                    read_u24(&[0, chunk.code[at], chunk.code[at + 1], chunk.code[at + 2]]);
+                print!(" {}:", chunk.constants[constant as usize]);
+            }
+            println!();
+            offset + 3 + named * 3
+        }
+        Ok(OpCode::OpUnpackList) => {
+            let count = chunk.code[offset + 1];
+            let rest = if chunk.code[offset + 2] == 1 {
+                " ..."
+            } else {
+                ""
+            };
+            println!("{:>-16} {:4}{}", "OpUnpackList", count, rest);
+            offset + 3
+        }
+        Ok(OpCode::OpUnpackFields) => {
+            let count = chunk.code[offset + 1] as usize;
+            print!("{:>-16} {:4}", "OpUnpackFields", count);
+            for i in 0..count {
+                let at = offset + 2 + i * 3;
+                let constant =
+                    read_u24(&[0, chunk.code[at], chunk.code[at + 1], chunk.code[at + 2]]);
+                print!(" {}", chunk.constants[constant as usize]);
+            }
+            println!();
+            offset + 2 + count * 3
+        }
+        Ok(OpCode::OpMethod) => {
+            let next = constant_instruction(chunk, "OpMethod", offset);
+            method_kind_operand(chunk, next)
+        }
+        Ok(OpCode::OpMethodLong) => {
+            let next = constant_long_instruction(chunk, "OpMethodLong", offset);
+            method_kind_operand(chunk, next)
+        }
+        Ok(op @ OpCode::OpInvoke) | Ok(op @ OpCode::OpSuperInvoke) => {
+            let next = constant_instruction(chunk, op.to_string().as_str(), offset);
+            println!(
+                "{:04}    |                     ({} args)",
+                next, chunk.code[next]
+            );
+            next + 1
+        }
+        Ok(op @ OpCode::OpInvokeLong) | Ok(op @ OpCode::OpSuperInvokeLong) => {
+            let next = constant_long_instruction(chunk, op.to_string().as_str(), offset);
+            println!(
+                "{:04}    |                     ({} args)",
+                next, chunk.code[next]
+            );
+            next + 1
+        }
+        Ok(OpCode::OpClosure) => {
+            let next = constant_instruction(chunk, "OpClosure", offset);
+            upvalue_operands(
+                chunk,
+                next,
+                &chunk.constants[chunk.code[offset + 1] as usize],
+            )
+        }
+        Ok(OpCode::OpClosureLong) => {
+            let next = constant_long_instruction(chunk, "OpClosureLong", offset);
+            let constant = read_u24(&[
+                0,
+                chunk.code[offset + 1],
+                chunk.code[offset + 2],
+                chunk.code[offset + 3],
+            ]);
+            upvalue_operands(chunk, next, &chunk.constants[constant as usize])
+        }
         Ok(op @ _) => simple_instruction(op.to_string().as_str(), offset),
         Err(err) => {
             println!("Unknown opcode {}", err);
@@ -46,8 +198,13 @@ fn constant_instruction(chunk: &Chunk, name: &str, offset: usize) -> usize {
 }
 
 fn constant_long_instruction(chunk: &Chunk, name: &str, offset: usize) -> usize {
-    assert!(chunk.code.len() > offset + 1);
-    let constant = read_u24(&chunk.code[offset + 1..=offset + 3]);
+    assert!(chunk.code.len() > offset + 3);
+    let constant = read_u24(&[
+        0,
+        chunk.code[offset + 1],
+        chunk.code[offset + 2],
+        chunk.code[offset + 3],
+    ]);
     print!("{:>-16} {:4} '", name, constant);
     assert!(chunk.constants.len() > constant as usize);
     print_value(&chunk.constants[constant as usize]);
@@ -55,11 +212,51 @@ fn constant_long_instruction(chunk: &Chunk, name: &str, offset: usize) -> usize
     offset + 4
 }
 
+fn byte_instruction(chunk: &Chunk, name: &str, offset: usize) -> usize {
+    assert!(chunk.code.len() > offset + 1);
+    let slot = chunk.code[offset + 1];
+    println!("{:>-16} {:4}", name, slot);
+    offset + 2
+}
+
+/// A slot operand followed by a forward jump taken depending on that slot.
+fn slot_jump_instruction(chunk: &Chunk, name: &str, offset: usize) -> usize {
+    let slot = chunk.code[offset + 1];
+    let jump = u16::from(chunk.code[offset + 2]) << 8 | u16::from(chunk.code[offset + 3]);
+    println!("{:>-16} {:4} -> {}", name, slot, offset + 4 + jump as usize);
+    offset + 4
+}
+
+fn method_kind_operand(chunk: &Chunk, offset: usize) -> usize {
+    let kind: Result<MethodKind, _> = chunk.code[offset].try_into();
+    match kind {
+        Ok(kind) => println!("{:04}    |                     {:?}", offset, kind),
+        Err(_) => println!("{:04}    |                     unknown method kind", offset),
+    }
+    offset + 1
+}
+
+fn upvalue_operands(chunk: &Chunk, mut offset: usize, function: &Slot) -> usize {
+    let count = function.as_function().map_or(0, |f| f.upvalue_count);
+    for _ in 0..count {
+        let is_local = chunk.code[offset];
+        let index = chunk.code[offset + 1];
+        println!(
+            "{:04}    |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+        offset += 2;
+    }
+    offset
+}
+
 fn simple_instruction(name: &str, offset: usize) -> usize {
     println!("{:>-16}", name);
     return offset + 1;
 }
 
-pub(crate) fn print_value(value: &Value) {
+pub(crate) fn print_value(value: &Slot) {
     print!("{}", value);
 }
@@ -9,16 +9,25 @@ pub enum Error {
         line: usize,
         msg: String,
     },
+    #[snafu(display("compile error at line {}: {}", line, msg))]
     CompileError {
         line: usize,
         msg: String,
     },
+    #[snafu(display("runtime error at line {}: {}", line, msg))]
     RuntimeError {
+        line: usize,
+        msg: String,
+    },
+    #[snafu(display("assertion failed at line {}: {}", line, msg))]
+    AssertionError {
+        line: usize,
         msg: String,
     },
     NoOpCodeError {
         msg: String,
     },
+    #[snafu(display("runtime error at line {}: {}: {}", line, source, msg))]
     TypeError {
         msg: String,
         line: usize,
@@ -35,6 +44,25 @@ pub enum Error {
         msg: String,
         source: std::num::ParseFloatError,
     },
+    ParseIntError {
+        line: usize,
+        msg: String,
+        source: num_bigint::ParseBigIntError,
+    },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Moves an error raised while running code to `line`. The dispatch loop
+    /// keeps the ip in a local, so errors are built with the line of the
+    /// frame's last call and moved to the failing instruction afterwards.
+    pub(crate) fn set_line(&mut self, line: usize) {
+        match self {
+            Error::RuntimeError { line: at, .. }
+            | Error::AssertionError { line: at, .. }
+            | Error::TypeError { line: at, .. } => *at = line,
+            _ => {}
+        }
+    }
+}
@@ -2,36 +2,62 @@ use std;
 use std::io;
 use std::io::{stdout, Read, Write};
 
+use crate::compiler::CompileOptions;
 use crate::vm::VM;
 use std::fs::File;
 use std::process::exit;
 
+mod checker;
 mod chunk;
 mod compiler;
 mod debug;
 mod error;
+mod object;
 mod scanner;
 mod token_type;
+mod types;
 mod value;
 mod vm;
 
 use error::{Error, Result};
 
 fn main() -> Result<()> {
-    let args = std::env::args().into_iter().collect::<Vec<String>>();
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
+    let mut args = std::env::args().skip(1).peekable();
+    let mut options = CompileOptions::default();
+    let mut stack_max = None;
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        match flag.as_str() {
+            "--no-asserts" => options.asserts = false,
+            "--no-fold" => options.fold_constants = false,
+            "--stack-max" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => stack_max = Some(n),
+                None => usage(),
+            },
+            _ => usage(),
+        }
+    }
+
+    let mut vm = VM::with_options(options);
+    if let Some(stack_max) = stack_max {
+        vm.set_stack_max(stack_max);
+    }
+    let args = args.collect::<Vec<String>>();
+    if args.is_empty() {
+        repl(vm);
+    } else if args.len() == 1 {
+        run_file(vm, &args[0]);
     } else {
-        eprintln!("Usage: rlox [path]");
-        std::process::exit(64);
+        usage();
     }
     Ok(())
 }
 
-fn repl() {
-    let mut vm = VM::new();
+fn usage() -> ! {
+    eprintln!("Usage: rlox [--no-asserts] [--no-fold] [--stack-max N] [path]");
+    exit(64)
+}
+
+fn repl(mut vm: VM) {
     let mut line = String::new();
     loop {
         print!("> ");
@@ -44,24 +70,34 @@ fn repl() {
         };
 
         match ret {
-            Ok(_) => (),
-            Err(e) => eprintln!("{}", e),
+            // Compile errors have already been reported by the compiler.
+            Ok(_) | Err(Error::CompileError { .. }) => (),
+            Err(e) => report(&mut vm, e),
         }
     }
 }
 
-fn run_file(path: &str) {
+/// Prints a runtime error and the stack trace it unwound.
+fn report(vm: &mut VM, err: Error) {
+    eprintln!("{}", err);
+    for line in vm.take_trace() {
+        eprintln!("{}", line);
+    }
+}
+
+fn run_file(mut vm: VM, path: &str) {
     let mut file = File::open(path).expect("open file");
     let mut source_bytes = Vec::new();
     let _size = file.read_to_end(&mut source_bytes).expect("read file");
     let source = String::from_utf8(source_bytes).expect("no valid utf-8");
-    let mut vm = VM::new();
     let ret = vm.interpret_source(&source);
 
     match ret {
         Err(Error::CompileError { .. }) => exit(65),
-        Err(Error::RuntimeError { .. }) => exit(70),
+        Err(e) => {
+            report(&mut vm, e);
+            exit(70)
+        }
         Ok(_) => (),
-        Err(_) => unreachable!(),
     }
 }
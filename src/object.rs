@@ -0,0 +1,472 @@
+use crate::chunk::Chunk;
+use crate::value::{Slot, Value};
+use crate::vm::CallFrame;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct Function {
+    /// Number of parameters, including ones with default values.
+    pub(crate) arity: usize,
+    /// Number of leading parameters without a default value.
+    pub(crate) required: usize,
+    pub(crate) params: Vec<String>,
+    /// Ends with a `...rest` parameter, which gets a list of the arguments
+    /// after the first `arity`. It isn't counted in `arity` or `params`.
+    pub(crate) has_rest: bool,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Chunk,
+    /// `None` for anonymous functions.
+    pub(crate) name: Option<String>,
+    /// Contains `yield`, so calling it returns a generator.
+    pub(crate) is_generator: bool,
+}
+
+impl Function {
+    pub fn new(name: Option<String>) -> Self {
+        Function {
+            arity: 0,
+            required: 0,
+            params: Vec::new(),
+            has_rest: false,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            is_generator: false,
+        }
+    }
+
+    /// The number of stack slots the parameters take.
+    pub(crate) fn param_slots(&self) -> usize {
+        self.arity + self.has_rest as usize
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<fn>"),
+        }
+    }
+}
+
+/// A captured variable. Open while the variable is still on the stack, closed
+/// over its value once it leaves.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Slot),
+    /// Points into the saved stack of a suspended generator. Reopened when the
+    /// generator resumes.
+    Parked(Rc<RefCell<Vec<Slot>>>, usize),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub(crate) function: Rc<Function>,
+    pub(crate) upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done,
+}
+
+/// A call of a generator function, suspended between `next()` calls.
+#[derive(Debug)]
+pub struct Generator {
+    pub(crate) closure: Rc<Closure>,
+    /// The frame's stack slots while suspended, starting with the callee.
+    pub(crate) stack: Rc<RefCell<Vec<Slot>>>,
+    /// Upvalues parked in `stack` while suspended.
+    pub(crate) upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    pub(crate) ip: usize,
+    pub(crate) args_passed: Vec<bool>,
+    pub(crate) state: GeneratorState,
+}
+
+impl fmt::Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.closure.function.name {
+            Some(name) => write!(f, "<generator {}>", name),
+            None => write!(f, "<generator>"),
+        }
+    }
+}
+
+/// Built-in classes, reachable as globals.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Builtin {
+    Fiber,
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Builtin::Fiber => write!(f, "Fiber"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FiberState {
+    /// Created by `Fiber.new()`, its function not called yet.
+    New,
+    /// Stopped in `Fiber.yield()`.
+    Suspended,
+    /// Executing, or waiting for a fiber it called.
+    Running,
+    Done,
+}
+
+/// A coroutine with its own value stack and call frames. Only the running
+/// fiber's stack and frames live in the `VM`; the others are kept here.
+#[derive(Debug)]
+pub struct Fiber {
+    pub(crate) stack: Rc<RefCell<Vec<Slot>>>,
+    pub(crate) frames: Vec<CallFrame>,
+    /// Upvalues parked in `stack` while the fiber isn't running.
+    pub(crate) upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// The fiber to return to on yield, return or error.
+    pub(crate) caller: Option<Rc<RefCell<Fiber>>>,
+    pub(crate) state: FiberState,
+    /// Called with `try()`, so errors inside it return to the caller.
+    pub(crate) is_try: bool,
+}
+
+impl Fiber {
+    pub fn new(stack: Vec<Slot>, state: FiberState) -> Self {
+        Fiber {
+            stack: Rc::new(RefCell::new(stack)),
+            frames: Vec::new(),
+            upvalues: Vec::new(),
+            caller: None,
+            state,
+            is_try: false,
+        }
+    }
+}
+
+impl fmt::Display for Fiber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<fiber>")
+    }
+}
+
+/// What a method declared in a class body is. Operand of `OpMethod`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum MethodKind {
+    Method,
+    Static,
+    Getter,
+    Setter,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub(crate) name: String,
+    /// Each table includes the entries copied down from the superclass.
+    pub(crate) methods: HashMap<String, Rc<Closure>>,
+    pub(crate) static_methods: HashMap<String, Rc<Closure>>,
+    pub(crate) getters: HashMap<String, Rc<Closure>>,
+    pub(crate) setters: HashMap<String, Rc<Closure>>,
+    /// Declared with `trait`: can be used by classes but not instantiated or
+    /// inherited from.
+    pub(crate) is_trait: bool,
+    /// Methods provided by more than one of the class's traits, with the
+    /// traits' names. The class body must override each of them.
+    pub(crate) conflicts: Vec<(MethodKind, String, String)>,
+}
+
+impl Class {
+    pub fn new(name: String, is_trait: bool) -> Self {
+        Class {
+            name,
+            methods: HashMap::new(),
+            static_methods: HashMap::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            is_trait,
+            conflicts: Vec::new(),
+        }
+    }
+
+    pub(crate) fn table(&self, kind: MethodKind) -> &HashMap<String, Rc<Closure>> {
+        match kind {
+            MethodKind::Method => &self.methods,
+            MethodKind::Static => &self.static_methods,
+            MethodKind::Getter => &self.getters,
+            MethodKind::Setter => &self.setters,
+        }
+    }
+
+    pub(crate) fn table_mut(&mut self, kind: MethodKind) -> &mut HashMap<String, Rc<Closure>> {
+        match kind {
+            MethodKind::Method => &mut self.methods,
+            MethodKind::Static => &mut self.static_methods,
+            MethodKind::Getter => &mut self.getters,
+            MethodKind::Setter => &mut self.setters,
+        }
+    }
+}
+
+impl MethodKind {
+    pub(crate) const ALL: [MethodKind; 4] = [
+        MethodKind::Method,
+        MethodKind::Static,
+        MethodKind::Getter,
+        MethodKind::Setter,
+    ];
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_trait {
+            write!(f, "<trait {}>", self.name)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub(crate) class: Rc<RefCell<Class>>,
+    pub(crate) fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<RefCell<Class>>) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn find_method(&self, name: &str) -> Option<Rc<Closure>> {
+        self.find(MethodKind::Method, name)
+    }
+
+    pub(crate) fn find(&self, kind: MethodKind, name: &str) -> Option<Rc<Closure>> {
+        self.class.borrow().table(kind).get(name).cloned()
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} instance", self.class.borrow().name)
+    }
+}
+
+/// A method looked up on an instance, remembering the instance as `this`.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub(crate) receiver: Value,
+    pub(crate) method: Rc<Closure>,
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
+
+/// An `enum` declaration: each variant's name and field names.
+#[derive(Debug)]
+pub struct Enum {
+    pub(crate) name: String,
+    pub(crate) variants: Vec<(String, Vec<String>)>,
+}
+
+impl Enum {
+    pub(crate) fn variant(&self, name: &str) -> Option<usize> {
+        self.variants
+            .iter()
+            .position(|(variant, _)| variant == name)
+    }
+
+    /// The value `Name.variant` evaluates to: the variant itself when it has
+    /// no fields, otherwise its constructor.
+    pub(crate) fn member(self: &Rc<Self>, tag: usize) -> Value {
+        if self.variants[tag].1.is_empty() {
+            Value::Variant(Rc::new(Variant {
+                enum_: self.clone(),
+                tag,
+                values: Vec::new(),
+            }))
+        } else {
+            Value::Constructor(Rc::new(Constructor {
+                enum_: self.clone(),
+                tag,
+            }))
+        }
+    }
+}
+
+impl fmt::Display for Enum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<enum {}>", self.name)
+    }
+}
+
+/// A value of an enum variant, with one value per field.
+#[derive(Debug)]
+pub struct Variant {
+    pub(crate) enum_: Rc<Enum>,
+    pub(crate) tag: usize,
+    pub(crate) values: Vec<Value>,
+}
+
+impl Variant {
+    pub(crate) fn name(&self) -> &str {
+        &self.enum_.variants[self.tag].0
+    }
+
+    pub(crate) fn field(&self, name: &str) -> Option<&Value> {
+        let fields = &self.enum_.variants[self.tag].1;
+        let index = fields.iter().position(|field| field == name)?;
+        self.values.get(index)
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.enum_.name, self.name())?;
+        if !self.values.is_empty() {
+            let values: Vec<String> = self.values.iter().map(Value::to_string).collect();
+            write!(f, "({})", values.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// `Name.variant` for a variant with fields, called to make a `Variant`.
+#[derive(Debug)]
+pub struct Constructor {
+    pub(crate) enum_: Rc<Enum>,
+    pub(crate) tag: usize,
+}
+
+impl Constructor {
+    pub(crate) fn arity(&self) -> usize {
+        self.enum_.variants[self.tag].1.len()
+    }
+}
+
+impl fmt::Display for Constructor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "<fn {}.{}>",
+            self.enum_.name, self.enum_.variants[self.tag].0
+        )
+    }
+}
+
+/// A `match` arm's pattern. Operand of `OpMatch`.
+#[derive(Debug)]
+pub enum Pattern {
+    /// `_`, matching anything.
+    Wildcard,
+    /// A name, matching anything and binding it to a new local.
+    Bind(String),
+    Literal(Value),
+    /// `Name.variant(fields)`, with a pattern per field.
+    Variant(Rc<Enum>, usize, Vec<Pattern>),
+}
+
+impl Pattern {
+    /// Whether `value` matches, appending the values it binds to `bindings`.
+    pub(crate) fn matches(&self, value: &Value, bindings: &mut Vec<Value>) -> bool {
+        match (self, value) {
+            (Pattern::Wildcard, _) => true,
+            (Pattern::Bind(_), _) => {
+                bindings.push(value.clone());
+                true
+            }
+            (Pattern::Literal(literal), _) => literal == value,
+            (Pattern::Variant(enum_, tag, fields), Value::Variant(variant)) => {
+                Rc::ptr_eq(enum_, &variant.enum_)
+                    && *tag == variant.tag
+                    && fields
+                        .iter()
+                        .zip(&variant.values)
+                        .all(|(field, value)| field.matches(value, bindings))
+            }
+            _ => false,
+        }
+    }
+
+    /// Matches every value.
+    pub(crate) fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Bind(_))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Bind(name) => write!(f, "{}", name),
+            Pattern::Literal(Value::Str(s)) => write!(f, "\"{}\"", s),
+            Pattern::Literal(value) => write!(f, "{}", value),
+            Pattern::Variant(enum_, tag, fields) => {
+                write!(f, "{}.{}", enum_.name, enum_.variants[*tag].0)?;
+                if !fields.is_empty() {
+                    let fields: Vec<String> = fields.iter().map(Pattern::to_string).collect();
+                    write!(f, "({})", fields.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `start..end` or `start..=end`. Lazy: iterating produces the integers in
+/// it one at a time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Range {
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+    pub(crate) inclusive: bool,
+}
+
+impl Range {
+    pub(crate) fn contains(&self, n: i64) -> bool {
+        n >= self.start && (n < self.end || (self.inclusive && n == self.end))
+    }
+
+    /// The range as start and exclusive end indices into a sequence of `len`
+    /// items, if it is within bounds.
+    pub(crate) fn slice_bounds(&self, len: usize) -> Option<(usize, usize)> {
+        let end = if self.inclusive {
+            self.end.checked_add(1)?
+        } else {
+            self.end
+        };
+        if self.start < 0 || self.start > end || end > len as i64 {
+            return None;
+        }
+        Some((self.start as usize, end as usize))
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = if self.inclusive { "..=" } else { ".." };
+        write!(f, "{}{}{}", self.start, op, self.end)
+    }
+}
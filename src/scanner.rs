@@ -1,14 +1,17 @@
 use crate::error::{self, Result};
 use crate::token_type::TokenType;
-use snafu::OptionExt;
+use unicode_xid::UnicodeXID;
 
 #[derive(Debug, PartialEq)]
 pub struct Token {
     pub(crate) ty: TokenType,
     pub(crate) lexeme: Vec<u8>,
     pub(crate) line: usize,
+    /// Byte offset of the lexeme in the source.
+    pub(crate) start: usize,
 }
 
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a [u8],
     start: usize,
@@ -26,11 +29,22 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    pub(crate) fn source(&self) -> &'a [u8] {
+        self.source
+    }
+
     pub fn scan_token(&mut self) -> Result<Token> {
-        self.skip_whitespace();
+        self.skip_whitespace()?;
 
         self.start = self.current;
 
+        if let Some(c) = self.peek_char() {
+            if c == '_' || c.is_xid_start() {
+                self.current += c.len_utf8();
+                return Ok(self.identifier());
+            }
+        }
+
         let c = if let Some(c) = self.advance() {
             c
         } else {
@@ -38,17 +52,7 @@ impl<'a> Scanner<'a> {
         };
 
         if c.is_ascii_digit() {
-            return self.number().context(error::ScanError {
-                msg: "invalid number",
-                line: self.line,
-            });
-        }
-
-        if c.is_ascii_alphabetic() {
-            return self.identifier().context(error::ScanError {
-                msg: "invalid identifier",
-                line: self.line,
-            });
+            return self.number(c);
         }
 
         let token = match c {
@@ -56,15 +60,36 @@ impl<'a> Scanner<'a> {
             b')' => self.make_token(TokenType::RightParen),
             b'{' => self.make_token(TokenType::LeftBrace),
             b'}' => self.make_token(TokenType::RightBrace),
+            b'[' => self.make_token(TokenType::LeftBracket),
+            b']' => self.make_token(TokenType::RightBracket),
             b';' => self.make_token(TokenType::Semicolon),
             b':' => self.make_token(TokenType::Colon),
             b',' => self.make_token(TokenType::Comma),
-            b'.' => self.make_token(TokenType::Dot),
+            b'.' => {
+                if !self.match_and_advance(b'.') {
+                    self.make_token(TokenType::Dot)
+                } else if self.match_and_advance(b'=') {
+                    self.make_token(TokenType::DotDotEqual)
+                } else if self.match_and_advance(b'.') {
+                    self.make_token(TokenType::DotDotDot)
+                } else {
+                    self.make_token(TokenType::DotDot)
+                }
+            }
             b'-' => self.make_token(TokenType::Minus),
             b'+' => self.make_token(TokenType::Plus),
             b'/' => self.make_token(TokenType::Slash),
-            b'?' => self.make_token(TokenType::QuestionMark),
+            b'?' => {
+                if self.match_and_advance(b'.') {
+                    self.make_token(TokenType::QuestionDot)
+                } else if self.match_and_advance(b'?') {
+                    self.make_token(TokenType::QuestionQuestion)
+                } else {
+                    self.make_token(TokenType::QuestionMark)
+                }
+            }
             b'*' => self.make_token(TokenType::Star),
+            b'%' => self.make_token(TokenType::Percent),
             b'!' => {
                 if self.match_and_advance(b'=') {
                     self.make_token(TokenType::BangEqual)
@@ -75,6 +100,8 @@ impl<'a> Scanner<'a> {
             b'=' => {
                 if self.match_and_advance(b'=') {
                     self.make_token(TokenType::EqualEqual)
+                } else if self.match_and_advance(b'>') {
+                    self.make_token(TokenType::Arrow)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
@@ -96,11 +123,15 @@ impl<'a> Scanner<'a> {
             b'"' => self.string()?,
 
             _ => {
+                // Skip the rest of a multi-byte character.
+                while self.peek().is_some_and(|c| c & 0xC0 == 0x80) {
+                    self.advance();
+                }
                 return error::ScanError {
                     msg: "unknown token",
-                    line: self.current,
+                    line: self.line,
                 }
-                .fail()
+                .fail();
             }
         };
         return Ok(token);
@@ -115,6 +146,7 @@ impl<'a> Scanner<'a> {
             ty,
             lexeme: self.source[self.start..self.current].to_vec(),
             line: self.line,
+            start: self.start,
         }
     }
 
@@ -141,33 +173,75 @@ impl<'a> Scanner<'a> {
         self.source.get(self.current).copied()
     }
 
+    /// Decodes the UTF-8 character at the current position, `None` at the end
+    /// of the source or on an invalid sequence.
+    fn peek_char(&self) -> Option<char> {
+        let bytes = &self.source[self.current..self.source.len().min(self.current + 4)];
+        let valid = match std::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).ok()?,
+        };
+        valid.chars().next()
+    }
+
     fn peek_next(&self) -> Option<u8> {
         self.source.get(self.current + 1).copied()
     }
 
-    fn skip_whitespace(&mut self) -> Option<()> {
-        loop {
-            let c = self.peek()?;
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(c) = self.peek() {
             match c {
                 b' ' | b'\r' | b'\t' => {
-                    self.advance()?;
+                    self.advance();
                 }
                 b'\n' => {
                     self.line += 1;
-                    self.advance()?;
-                }
-                b'/' => {
-                    if self.peek_next()? == b'/' {
-                        while self.peek()? != b'\n' {
-                            self.advance()?;
-                        }
-                    } else {
-                        return None;
+                    self.advance();
+                }
+                b'/' if self.peek_next() == Some(b'/') => {
+                    while self.peek().is_some_and(|c| c != b'\n') {
+                        self.advance();
                     }
                 }
-                _ => return None,
+                b'/' if self.peek_next() == Some(b'*') => self.block_comment()?,
+                _ => break,
             };
         }
+        Ok(())
+    }
+
+    /// `/* ... */` comments, which may nest.
+    fn block_comment(&mut self) -> Result<()> {
+        let start_line = self.line;
+        let mut depth = 0;
+        loop {
+            match (self.peek(), self.peek_next()) {
+                (Some(b'/'), Some(b'*')) => {
+                    self.current += 2;
+                    depth += 1;
+                }
+                (Some(b'*'), Some(b'/')) => {
+                    self.current += 2;
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                (Some(c), _) => {
+                    if c == b'\n' {
+                        self.line += 1;
+                    }
+                    self.advance();
+                }
+                (None, _) => {
+                    return error::ScanError {
+                        msg: "Unterminated block comment",
+                        line: start_line,
+                    }
+                    .fail()
+                }
+            }
+        }
     }
 
     fn string(&mut self) -> Result<Token> {
@@ -193,53 +267,294 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn number(&mut self) -> Option<Token> {
-        while self.peek()?.is_ascii_digit() {
-            self.advance()?;
+    /// Decimal (`1_000`, `1.5`, `2.5e-3`) and radix-prefixed (`0xff`, `0b1010`,
+    /// `0o755`) literals. `_` may be used as a separator between digits.
+    fn number(&mut self, first: u8) -> Result<Token> {
+        let radix = match (first, self.peek()) {
+            (b'0', Some(b'x')) | (b'0', Some(b'X')) => Some(16),
+            (b'0', Some(b'o')) | (b'0', Some(b'O')) => Some(8),
+            (b'0', Some(b'b')) | (b'0', Some(b'B')) => Some(2),
+            _ => None,
+        };
+
+        if let Some(radix) = radix {
+            self.advance();
+            if self.digits(radix, false)? == 0 {
+                return self.number_error("missing digits after radix prefix");
+            }
+            return self.finish_number();
         }
 
-        if self.peek()? == b'.' && self.peek_next()?.is_ascii_digit() {
-            self.advance()?;
+        self.digits(10, true)?;
+
+        if self.peek() == Some(b'.') && self.peek_next() == Some(b'_') {
+            return self.number_error("'_' must be between digits");
+        }
+        if self.peek() == Some(b'.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+            self.digits(10, false)?;
+        }
 
-            while self.peek()?.is_ascii_digit() {
-                self.advance()?;
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.advance();
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.advance();
+            }
+            if self.digits(10, false)? == 0 {
+                return self.number_error("missing digits in exponent");
             }
         }
-        Some(self.make_token(TokenType::Number))
+
+        self.finish_number()
     }
 
-    fn identifier(&mut self) -> Option<Token> {
-        loop {
-            let c = self.peek()?;
-            if c.is_ascii_alphabetic() || c.is_ascii_digit() {
-                self.advance()?;
+    /// Consumes digits of the given radix and the `_` separators between
+    /// them, returning the number of digits consumed. `after_digit` is set
+    /// when the digit before the current position belongs to the same run.
+    fn digits(&mut self, radix: u32, after_digit: bool) -> Result<usize> {
+        let mut count = 0;
+        while let Some(c) = self.peek() {
+            if c == b'_' {
+                let next_is_digit = self
+                    .peek_next()
+                    .is_some_and(|next| (next as char).is_digit(radix));
+                if (count == 0 && !after_digit) || !next_is_digit {
+                    self.number_error("'_' must be between digits")?;
+                }
+            } else if (c as char).is_digit(radix) {
+                count += 1;
             } else {
                 break;
             }
+            self.advance();
+        }
+        Ok(count)
+    }
+
+    fn finish_number(&mut self) -> Result<Token> {
+        if self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+        {
+            return self.number_error("invalid digit in number");
+        }
+        Ok(self.make_token(TokenType::Number))
+    }
+
+    fn number_error(&mut self, msg: &str) -> Result<Token> {
+        // Skip the rest of the malformed literal so it doesn't produce more errors.
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+        {
+            self.advance();
+        }
+        error::ScanError {
+            msg,
+            line: self.line,
+        }
+        .fail()
+    }
+
+    fn identifier(&mut self) -> Token {
+        while let Some(c) = self.peek_char() {
+            if !c.is_xid_continue() {
+                break;
+            }
+            self.current += c.len_utf8();
         }
 
-        Some(self.make_token(self.identifier_type()))
+        self.make_token(self.identifier_type())
     }
 
     fn identifier_type(&self) -> TokenType {
         match &self.source[self.start..self.current] {
             b"and" => TokenType::And,
+            b"assert" => TokenType::Assert,
             b"class" => TokenType::Class,
+            b"const" => TokenType::Const,
             b"else" => TokenType::Else,
+            b"enum" => TokenType::Enum,
+            b"get" => TokenType::Get,
             b"if" => TokenType::If,
+            b"in" => TokenType::In,
+            b"let" => TokenType::Let,
+            b"match" => TokenType::Match,
             b"nil" => TokenType::Nil,
             b"or" => TokenType::Or,
             b"print" => TokenType::Print,
             b"return" => TokenType::Return,
+            b"set" => TokenType::Set,
+            b"static" => TokenType::Static,
             b"super" => TokenType::Super,
             b"var" => TokenType::Var,
             b"while" => TokenType::While,
+            b"with" => TokenType::With,
+            b"yield" => TokenType::Yield,
             b"false" => TokenType::False,
             b"for" => TokenType::For,
             b"fun" => TokenType::Fun,
             b"this" => TokenType::This,
+            b"trait" => TokenType::Trait,
             b"true" => TokenType::True,
             _ => TokenType::Identifier,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn scan_number(source: &str) -> Result<Token> {
+        Scanner::new(source.as_bytes()).scan_token()
+    }
+
+    #[test]
+    fn test_number_literals() {
+        for source in &[
+            "1",
+            "1.5",
+            "1e10",
+            "1E-3",
+            "2.5e+4",
+            "0xff",
+            "0b1010",
+            "0o755",
+            "1_000_000",
+        ] {
+            let token = scan_number(source).unwrap();
+            assert_eq!(token.ty, TokenType::Number);
+            assert_eq!(token.lexeme, source.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_malformed_number_literals() {
+        for source in &["0x", "1e", "1e+", "1_", "0b102", "12abc"] {
+            assert!(scan_number(source).is_err(), "{} should not scan", source);
+        }
+    }
+
+    #[test]
+    fn test_misplaced_separators() {
+        for source in &[
+            "1__0", "1_.5", "1._5", "1.5_", "0x_ff", "0b_1", "0o7_", "1_e5", "1e_5", "1e+_5",
+        ] {
+            match scan_number(source) {
+                Err(Error::ScanError { msg, .. }) => {
+                    assert_eq!(msg, "'_' must be between digits", "{}", source)
+                }
+                other => panic!("{} should not scan: {:?}", source, other),
+            }
+        }
+        for source in &["1_0", "0xf_f", "1_0.2_5e1_0", "0b1_0"] {
+            assert_eq!(scan_number(source).unwrap().lexeme, source.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_identifiers() {
+        let mut scanner = Scanner::new("my_var _x größe 变量 x1 and".as_bytes());
+        for expected in &["my_var", "_x", "größe", "变量", "x1"] {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.ty, TokenType::Identifier);
+            assert_eq!(token.lexeme, expected.as_bytes());
+        }
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::And);
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_nested_block_comments() {
+        let mut scanner = Scanner::new(b"/* a /* b\n */ c\n */ 1 // d\n/**/2");
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(token.lexeme, b"1");
+        assert_eq!(token.line, 3);
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(token.lexeme, b"2");
+        assert_eq!(token.line, 4);
+    }
+
+    #[test]
+    fn test_unterminated_block_comment() {
+        let mut scanner = Scanner::new(b"1\n/* a /* b */\n");
+        scanner.scan_token().unwrap();
+        match scanner.scan_token() {
+            Err(Error::ScanError { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_arrow() {
+        let mut scanner = Scanner::new(b"=> = ==");
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::Arrow);
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::Equal);
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::EqualEqual);
+    }
+
+    #[test]
+    fn test_number_followed_by_dot() {
+        let mut scanner = Scanner::new(b"1.");
+        assert_eq!(scanner.scan_token().unwrap().lexeme, b"1");
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::Dot);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut scanner = Scanner::new(b"1..5 a.b 1.5..=2 ...x");
+        for (ty, lexeme) in &[
+            (TokenType::Number, &b"1"[..]),
+            (TokenType::DotDot, b".."),
+            (TokenType::Number, b"5"),
+            (TokenType::Identifier, b"a"),
+            (TokenType::Dot, b"."),
+            (TokenType::Identifier, b"b"),
+            (TokenType::Number, b"1.5"),
+            (TokenType::DotDotEqual, b"..="),
+            (TokenType::Number, b"2"),
+            (TokenType::DotDotDot, b"..."),
+            (TokenType::Identifier, b"x"),
+        ] {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.ty, *ty);
+            assert_eq!(token.lexeme, *lexeme);
+        }
+    }
+
+    #[test]
+    fn test_question_tokens() {
+        let mut scanner = Scanner::new(b"a?.b ?? c ? d");
+        for ty in &[
+            TokenType::Identifier,
+            TokenType::QuestionDot,
+            TokenType::Identifier,
+            TokenType::QuestionQuestion,
+            TokenType::Identifier,
+            TokenType::QuestionMark,
+            TokenType::Identifier,
+        ] {
+            assert_eq!(scanner.scan_token().unwrap().ty, *ty);
+        }
+    }
+
+    #[test]
+    fn test_contextual_keywords() {
+        let mut scanner = Scanner::new(b"get set static trait with enum getter");
+        for ty in &[
+            TokenType::Get,
+            TokenType::Set,
+            TokenType::Static,
+            TokenType::Trait,
+            TokenType::With,
+            TokenType::Enum,
+        ] {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.ty, *ty);
+            assert!(token.ty.is_identifier());
+        }
+        assert_eq!(scanner.scan_token().unwrap().ty, TokenType::Identifier);
+    }
+}
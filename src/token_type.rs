@@ -10,6 +10,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -18,6 +20,7 @@ pub enum TokenType {
     Colon,
     Slash,
     Star,
+    Percent,
     QuestionMark,
 
     // One or two character tokens.
@@ -25,10 +28,16 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
+    DotDot,
+    DotDotEqual,
+    DotDotDot,
+    QuestionDot,
+    QuestionQuestion,
 
     // Literals.
     Identifier,
@@ -37,12 +46,16 @@ pub enum TokenType {
 
     // Keywords.
     And,
+    Assert,
     Class,
+    Const,
     Else,
     False,
     For,
     Fun,
     If,
+    In,
+    Match,
     Nil,
     Or,
     Print,
@@ -52,6 +65,34 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Yield,
+
+    // Contextual keywords, only special in class bodies or at the start of a
+    // declaration.
+    Get,
+    Set,
+    Static,
+    Trait,
+    With,
+    Enum,
+    Let,
 
     Eof,
 }
+
+impl TokenType {
+    /// Identifiers, including contextual keywords used as names.
+    pub fn is_identifier(self) -> bool {
+        matches!(
+            self,
+            TokenType::Identifier
+                | TokenType::Get
+                | TokenType::Set
+                | TokenType::Static
+                | TokenType::Trait
+                | TokenType::With
+                | TokenType::Enum
+                | TokenType::Let
+        )
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// The static type of an expression or variable, from optional annotations
+/// like `var x: Number` and what the compiler can tell about literals and
+/// operators. Unannotated variables are `Any` and never checked.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Type {
+    Any,
+    Nil,
+    Bool,
+    /// Integers and floats alike.
+    Number,
+    String,
+    /// A function, with its signature when it is known.
+    Function(Option<Rc<Signature>>),
+    /// An instance of the named class or one of its subclasses.
+    Class(String),
+    /// The named class itself, which makes instances when called.
+    ClassObject(String),
+}
+
+/// Every class declared so far, with the name of its superclass.
+pub(crate) type Classes = HashMap<String, Option<String>>;
+
+/// Where a static type comes from. Only types that trace back to an
+/// annotation are checked: unannotated code like `"a" - 1` compiles and
+/// fails at runtime, if it runs at all.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Origin {
+    /// Worked out from literals and declarations alone.
+    Inferred,
+    /// Declared by an annotation, or computed from something that was.
+    Annotated,
+}
+
+/// The type of an expression as the compiler records it while parsing. The
+/// checker resolves it once the whole program has been parsed, so it can
+/// refer to globals and classes declared further down.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TypeExpr {
+    Known(Type, Origin),
+    /// A global that wasn't declared yet where it was used.
+    Global(String),
+    /// The result of calling a value of this type.
+    Call(Box<TypeExpr>),
+    Negate(Box<TypeExpr>),
+    Binary(String, Box<TypeExpr>, Box<TypeExpr>),
+    /// `left ?? right`, typed only when both sides have the same type.
+    Coalesce(Box<TypeExpr>, Box<TypeExpr>),
+}
+
+impl TypeExpr {
+    pub(crate) fn any() -> Self {
+        TypeExpr::Known(Type::Any, Origin::Inferred)
+    }
+
+    pub(crate) fn inferred(ty: Type) -> Self {
+        TypeExpr::Known(ty, Origin::Inferred)
+    }
+
+    pub(crate) fn annotated(ty: Type) -> Self {
+        TypeExpr::Known(ty, Origin::Annotated)
+    }
+}
+
+/// Parameter and return types of a function declared with annotations.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Signature {
+    pub(crate) name: Option<String>,
+    pub(crate) params: Vec<(String, Type)>,
+    pub(crate) ret: Type,
+}
+
+impl Type {
+    /// The type an annotation names. Unknown names are taken to be classes.
+    pub(crate) fn from_name(name: &str) -> Self {
+        match name {
+            "Any" => Type::Any,
+            "Nil" => Type::Nil,
+            "Bool" => Type::Bool,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            "Function" => Type::Function(None),
+            _ => Type::Class(name.to_string()),
+        }
+    }
+
+    /// Whether values of type `other` can be stored where `self` is declared.
+    /// An instance is accepted where its class or any superclass is.
+    pub(crate) fn accepts(&self, other: &Type, classes: &Classes) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Function(_), Type::Function(_)) => true,
+            (Type::Class(expected), Type::Class(class)) => {
+                let mut class = Some(class);
+                // Bounded by the number of classes in case of a cycle
+                // between redeclared classes.
+                for _ in 0..=classes.len() {
+                    match class {
+                        Some(name) if name == expected => return true,
+                        Some(name) => class = classes.get(name).and_then(Option::as_ref),
+                        None => return false,
+                    }
+                }
+                false
+            }
+            (l, r) => l == r,
+        }
+    }
+
+    /// Known types whose operators can't be overloaded.
+    fn is_builtin(&self) -> bool {
+        !matches!(self, Type::Any | Type::Class(_) | Type::ClassObject(_))
+    }
+
+    /// The type of `-operand`, or the error to report.
+    pub(crate) fn negate(&self) -> Result<Type, String> {
+        match self {
+            Type::Number => Ok(Type::Number),
+            ty if ty.is_builtin() => {
+                Err(format!("Operand of '-' must be a number but got {}.", ty))
+            }
+            _ => Ok(Type::Any),
+        }
+    }
+
+    /// The type of `left symbol right`, or the error to report. Operands that
+    /// may be instances can overload the operator, so only built-in operand
+    /// types are checked.
+    pub(crate) fn binary(symbol: &str, left: &Type, right: &Type) -> Result<Type, String> {
+        if !left.is_builtin() || !right.is_builtin() {
+            return Ok(Type::Any);
+        }
+        match (symbol, left, right) {
+            ("==", _, _) | ("!=", _, _) => Ok(Type::Bool),
+            ("+", Type::Number, Type::Number) | ("+", Type::String, Type::String) => {
+                Ok(left.clone())
+            }
+            ("+", _, _) => Err(format!(
+                "Operands of '+' must be two numbers or two strings but got {} and {}.",
+                left, right
+            )),
+            ("<", Type::Number, Type::Number)
+            | ("<=", Type::Number, Type::Number)
+            | (">", Type::Number, Type::Number)
+            | (">=", Type::Number, Type::Number) => Ok(Type::Bool),
+            ("..", Type::Number, Type::Number) | ("..=", Type::Number, Type::Number) => {
+                Ok(Type::Any)
+            }
+            (_, Type::Number, Type::Number) => Ok(Type::Number),
+            _ => Err(format!(
+                "Operands of '{}' must be numbers but got {} and {}.",
+                symbol, left, right
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Nil => write!(f, "Nil"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::Function(_) => write!(f, "Function"),
+            Type::Class(name) => write!(f, "{}", name),
+            Type::ClassObject(name) => write!(f, "class {}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts() {
+        let classes = Classes::new();
+        assert!(Type::Number.accepts(&Type::Number, &classes));
+        assert!(Type::Number.accepts(&Type::Any, &classes));
+        assert!(Type::Any.accepts(&Type::String, &classes));
+        assert!(!Type::Number.accepts(&Type::String, &classes));
+        assert!(!Type::String.accepts(&Type::Nil, &classes));
+        assert!(!Type::from_name("Point").accepts(&Type::Number, &classes));
+    }
+
+    #[test]
+    fn test_accepts_subclasses() {
+        let mut classes = Classes::new();
+        classes.insert("Shape".to_string(), None);
+        classes.insert("Point".to_string(), Some("Shape".to_string()));
+        classes.insert("Pixel".to_string(), Some("Point".to_string()));
+        classes.insert("Color".to_string(), None);
+        let shape = Type::from_name("Shape");
+        let pixel = Type::from_name("Pixel");
+        assert!(shape.accepts(&pixel, &classes));
+        assert!(pixel.accepts(&pixel, &classes));
+        assert!(!pixel.accepts(&shape, &classes));
+        assert!(!shape.accepts(&Type::from_name("Color"), &classes));
+        assert!(!shape.accepts(&Type::from_name("Unknown"), &classes));
+
+        // Redeclaring a class can make a cycle.
+        classes.insert("Shape".to_string(), Some("Pixel".to_string()));
+        assert!(!Type::from_name("Color").accepts(&pixel, &classes));
+    }
+
+    #[test]
+    fn test_binary() {
+        assert_eq!(
+            Type::binary("+", &Type::String, &Type::String),
+            Ok(Type::String)
+        );
+        assert_eq!(
+            Type::binary("<", &Type::Number, &Type::Number),
+            Ok(Type::Bool)
+        );
+        assert_eq!(Type::binary("*", &Type::Number, &Type::Any), Ok(Type::Any));
+        assert_eq!(
+            Type::binary("-", &Type::Class("V".into()), &Type::String),
+            Ok(Type::Any)
+        );
+        assert!(Type::binary("+", &Type::Number, &Type::String).is_err());
+        assert!(Type::binary("<", &Type::String, &Type::String).is_err());
+        assert_eq!(Type::binary("==", &Type::Nil, &Type::Bool), Ok(Type::Bool));
+        assert!(Type::String.negate().is_err());
+    }
+}
@@ -1,11 +1,35 @@
+use crate::object::{
+    BoundMethod, Builtin, Class, Closure, Constructor, Enum, Fiber, Function, Generator, Instance,
+    Pattern, Range, Variant,
+};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
 use paste;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::ops::Deref;
+use std::rc::Rc;
 use std::result::Result;
 
+#[cfg(feature = "nan-boxing")]
+mod nan_box;
+
+#[cfg(feature = "nan-boxing")]
+pub(crate) use nan_box::{Borrowed, NanBox};
+
+/// What the VM keeps on its stacks and in constant tables: a `NanBox` with
+/// the `nan-boxing` feature and the `Value` itself without it. Either has the
+/// accessors of `Value`, `with` and conversions to and from `Value`.
+#[cfg(feature = "nan-boxing")]
+pub(crate) type Slot = NanBox;
+#[cfg(not(feature = "nan-boxing"))]
+pub(crate) type Slot = Value;
+
 #[derive(Debug)]
 pub struct ValueTypeError {
-    msg: String,
+    msg: &'static str,
 }
 
 impl fmt::Display for ValueTypeError {
@@ -16,6 +40,20 @@ impl fmt::Display for ValueTypeError {
 
 impl std::error::Error for ValueTypeError {}
 
+impl ValueTypeError {
+    /// The message alone, for errors reported to Lox programs.
+    pub(crate) fn msg(&self) -> &'static str {
+        self.msg
+    }
+}
+
+const NUMBER_OPERAND: ValueTypeError = ValueTypeError {
+    msg: "Operand must be a number.",
+};
+const NUMBER_OPERANDS: ValueTypeError = ValueTypeError {
+    msg: "Operands must be numbers.",
+};
+
 macro_rules! impl_enum_variant {
     ($name:tt, $enum_ty:tt, $variant:tt, $ty:ty) => {
         impl $enum_ty {
@@ -25,7 +63,7 @@ macro_rules! impl_enum_variant {
                     match self {
                         $enum_ty::$variant(v) => Ok(v.clone()),
                         _ => Err(ValueTypeError {
-                            msg: format!("Operand must be a {}", stringify!($ty)),
+                            msg: concat!("Operand must be a ", stringify!($ty)),
                         }),
                     }
                 }
@@ -35,7 +73,7 @@ macro_rules! impl_enum_variant {
                     match self {
                         $enum_ty::$variant(v) => Ok(v),
                         _ => Err(ValueTypeError {
-                            msg: format!("Operand must be a {}", stringify!($ty)),
+                            msg: concat!("Operand must be a ", stringify!($ty)),
                         }),
                     }
                 }
@@ -45,7 +83,7 @@ macro_rules! impl_enum_variant {
                     match self {
                         $enum_ty::$variant(v) => Ok(v),
                         _ => Err(ValueTypeError {
-                            msg: format!("Operand must be a {}", stringify!($ty)),
+                            msg: concat!("Operand must be a ", stringify!($ty)),
                         }),
                     }
                 }
@@ -65,22 +103,121 @@ macro_rules! impl_enum_variant {
                 $enum_ty::$variant(v)
             }
         }
+
+        // The packed representation unpacks and defers to the accessors above.
+        // `as_` is only there for variants that are always on the heap, since
+        // inline values have nothing to borrow. Variants that hold an `Rc`
+        // lend it out instead, as the box keeps no `Rc` to borrow.
+        #[cfg(feature = "nan-boxing")]
+        impl NanBox {
+            paste::item! {
+                #[allow(dead_code)]
+                pub(crate) fn [<to_ $name>](&self) -> Result<$ty, ValueTypeError> {
+                    self.with(|v| v.[<to_ $name>]())
+                }
+
+                #[allow(dead_code)]
+                pub(crate) fn [<into_ $name>](self) -> Result<$ty, ValueTypeError> {
+                    $enum_ty::from(self).[<into_ $name>]()
+                }
+
+                #[allow(dead_code)]
+                pub(crate) fn [<is_ $name>](&self) -> bool {
+                    self.with(|v| v.[<is_ $name>]())
+                }
+            }
+        }
+    };
+    ($name:tt, $enum_ty:tt, $variant:tt, $ty:ty, boxed) => {
+        impl_enum_variant!($name, $enum_ty, $variant, $ty);
+
+        #[cfg(feature = "nan-boxing")]
+        impl NanBox {
+            paste::item! {
+                #[allow(dead_code)]
+                pub(crate) fn [<as_ $name>](&self) -> Result<&$ty, ValueTypeError> {
+                    match self.heap() {
+                        Some(v) => v.[<as_ $name>](),
+                        None => Err(ValueTypeError {
+                            msg: concat!("Operand must be a ", stringify!($ty)),
+                        }),
+                    }
+                }
+            }
+        }
+    };
+    ($name:tt, $enum_ty:tt, $variant:tt, $ty:ty, rc) => {
+        impl_enum_variant!($name, $enum_ty, $variant, $ty);
+
+        #[cfg(feature = "nan-boxing")]
+        impl NanBox {
+            paste::item! {
+                #[allow(dead_code)]
+                pub(crate) fn [<as_ $name>](&self) -> Result<Borrowed<'_, $ty>, ValueTypeError> {
+                    self.lend($enum_ty::[<as_ $name>]).ok_or(ValueTypeError {
+                        msg: concat!("Operand must be a ", stringify!($ty)),
+                    })
+                }
+            }
+        }
     };
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
+    Int(i64),
+    /// Integers outside the i64 range. Always normalized back to `Int` when the
+    /// value fits, so an integer has exactly one representation.
+    BigInt(BigInt),
     Str(String),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Generator(Rc<RefCell<Generator>>),
+    Fiber(Rc<RefCell<Fiber>>),
+    Builtin(Builtin),
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    Enum(Rc<Enum>),
+    Variant(Rc<Variant>),
+    Constructor(Rc<Constructor>),
+    Range(Range),
+    List(Rc<RefCell<Vec<Value>>>),
+    /// String keys, iterated in sorted order.
+    Map(Rc<RefCell<BTreeMap<String, Value>>>),
+    /// Only ever a constant, the operand of `OpMatch`.
+    Pattern(Rc<Pattern>),
 }
 
 impl_enum_variant!(bool, Value, Bool, bool);
 impl_enum_variant!(number, Value, Number, f64);
-impl_enum_variant!(str, Value, Str, String);
+impl_enum_variant!(int, Value, Int, i64);
+impl_enum_variant!(bigint, Value, BigInt, BigInt, boxed);
+impl_enum_variant!(str, Value, Str, String, boxed);
+impl_enum_variant!(function, Value, Function, Rc<Function>, rc);
+impl_enum_variant!(closure, Value, Closure, Rc<Closure>, rc);
+impl_enum_variant!(generator, Value, Generator, Rc<RefCell<Generator>>, rc);
+impl_enum_variant!(fiber, Value, Fiber, Rc<RefCell<Fiber>>, rc);
+impl_enum_variant!(builtin, Value, Builtin, Builtin, boxed);
+impl_enum_variant!(class, Value, Class, Rc<RefCell<Class>>, rc);
+impl_enum_variant!(instance, Value, Instance, Rc<RefCell<Instance>>, rc);
+impl_enum_variant!(bound_method, Value, BoundMethod, Rc<BoundMethod>, rc);
+impl_enum_variant!(enum, Value, Enum, Rc<Enum>, rc);
+impl_enum_variant!(variant, Value, Variant, Rc<Variant>, rc);
+impl_enum_variant!(constructor, Value, Constructor, Rc<Constructor>, rc);
+impl_enum_variant!(range, Value, Range, Range, boxed);
+impl_enum_variant!(list, Value, List, Rc<RefCell<Vec<Value>>>, rc);
+impl_enum_variant!(map, Value, Map, Rc<RefCell<BTreeMap<String, Value>>>, rc);
+impl_enum_variant!(pattern, Value, Pattern, Rc<Pattern>, rc);
 
 impl Value {
+    pub(crate) fn new_list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+
     pub(crate) fn is_nil(&self) -> bool {
         match self {
             Value::Nil => true,
@@ -91,20 +228,285 @@ impl Value {
     pub(crate) fn is_falsey(&self) -> bool {
         self.is_nil() || (self.is_bool() && !self.to_bool().expect("not bool"))
     }
+
+    pub(crate) fn is_integer(&self) -> bool {
+        self.is_int() || self.is_bigint()
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) fn into_slot(self) -> Slot {
+        NanBox::from(self)
+    }
+
+    #[cfg(not(feature = "nan-boxing"))]
+    pub(crate) fn into_slot(self) -> Slot {
+        self
+    }
+
+    /// The counterparts of the `NanBox` methods, for code that handles a
+    /// `Slot` of either kind.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&Value) -> R) -> R {
+        f(self)
+    }
+
+    #[cfg(not(feature = "nan-boxing"))]
+    pub(crate) fn into_value(self) -> Value {
+        self
+    }
+
+    #[cfg(not(feature = "nan-boxing"))]
+    pub(crate) fn to_value(&self) -> Value {
+        self.clone()
+    }
+
+    /// The integer of an `Int`, for fast paths that skip building the error
+    /// `to_int` returns for anything else.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_bigint(v: BigInt) -> Value {
+        match v.to_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::BigInt(v),
+        }
+    }
+
+    fn to_big(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(v) => Some(BigInt::from(*v)),
+            Value::BigInt(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    /// Applies an integer operation if both operands are integers, trying the
+    /// i64 version first and redoing it on BigInt when that overflows. `None`
+    /// if either operand is not an integer.
+    pub(crate) fn integer_op(
+        left: &Value,
+        right: &Value,
+        int_op: fn(i64, i64) -> Option<i64>,
+        big_op: fn(BigInt, BigInt) -> BigInt,
+    ) -> Option<Value> {
+        if let (Value::Int(l), Value::Int(r)) = (left, right) {
+            if let Some(v) = int_op(*l, *r) {
+                return Some(Value::Int(v));
+            }
+        }
+        Some(Value::from_bigint(big_op(left.to_big()?, right.to_big()?)))
+    }
+
+    /// The result of an arithmetic operator on two numbers: an integer when
+    /// both are integers, otherwise a float.
+    pub(crate) fn arithmetic(
+        left: &Value,
+        right: &Value,
+        int_op: fn(i64, i64) -> Option<i64>,
+        big_op: fn(BigInt, BigInt) -> BigInt,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<Value, ValueTypeError> {
+        match Value::integer_op(left, right, int_op, big_op) {
+            Some(value) => Ok(value),
+            None => match (left.to_f64(), right.to_f64()) {
+                (Ok(l), Ok(r)) => Ok(float_op(l, r).into()),
+                _ => Err(NUMBER_OPERANDS),
+            },
+        }
+    }
+
+    /// `left / right` for two numbers. Integers that divide evenly give an
+    /// integer; any other quotient is a float, so `7 / 2` is `3.5`.
+    pub(crate) fn divide(left: &Value, right: &Value) -> Result<Value, ValueTypeError> {
+        if let (Value::Int(l), Value::Int(r)) = (left, right) {
+            if l.checked_rem(*r) == Some(0) {
+                if let Some(v) = l.checked_div(*r) {
+                    return Ok(Value::Int(v));
+                }
+            }
+        }
+        if let (Some(l), Some(r)) = (left.to_big(), right.to_big()) {
+            if !r.is_zero() && (&l % &r).is_zero() {
+                return Ok(Value::from_bigint(l / r));
+            }
+        }
+        match (left.to_f64(), right.to_f64()) {
+            (Ok(l), Ok(r)) => Ok((l / r).into()),
+            _ => Err(NUMBER_OPERANDS),
+        }
+    }
+
+    /// `-self` for a number.
+    pub(crate) fn negate(self) -> Result<Value, ValueTypeError> {
+        Ok(match self {
+            Value::Int(v) => match v.checked_neg() {
+                Some(v) => v.into(),
+                None => Value::from_bigint(-BigInt::from(v)),
+            },
+            Value::BigInt(v) => Value::from_bigint(-v),
+            Value::Number(v) => (-v).into(),
+            _ => return Err(NUMBER_OPERAND),
+        })
+    }
+
+    /// Int, BigInt or Number, widened to f64. Used when an integer meets a
+    /// float in arithmetic.
+    pub(crate) fn to_f64(&self) -> Result<f64, ValueTypeError> {
+        match self {
+            Value::Number(v) => Ok(*v),
+            Value::Int(v) => Ok(*v as f64),
+            Value::BigInt(v) => Ok(v.to_f64().unwrap_or_else(|| {
+                if v.is_positive() {
+                    f64::INFINITY
+                } else {
+                    f64::NEG_INFINITY
+                }
+            })),
+            _ => Err(NUMBER_OPERAND),
+        }
+    }
+
+    /// Numeric ordering across Int, BigInt and Number. Integers are compared
+    /// exactly against floats, without rounding them to f64 first. `None` if
+    /// either side is NaN.
+    pub(crate) fn compare_numbers(
+        &self,
+        other: &Value,
+    ) -> Result<Option<Ordering>, ValueTypeError> {
+        match (self, other) {
+            (Value::Int(l), Value::Int(r)) => Ok(Some(l.cmp(r))),
+            (Value::Int(l), Value::Number(r)) => Ok(compare_int_float(*l, *r)),
+            (Value::Number(l), Value::Int(r)) => {
+                Ok(compare_int_float(*r, *l).map(Ordering::reverse))
+            }
+            (Value::Number(l), Value::Number(r)) => Ok(l.partial_cmp(r)),
+            (Value::BigInt(l), Value::Number(r)) => Ok(compare_bigint_float(l, *r)),
+            (Value::Number(l), Value::BigInt(r)) => {
+                Ok(compare_bigint_float(r, *l).map(Ordering::reverse))
+            }
+            (l, r) if l.is_integer() && r.is_integer() => Ok(l.to_big().partial_cmp(&r.to_big())),
+            _ => Err(NUMBER_OPERANDS),
+        }
+    }
+}
+
+fn compare_bigint_float(b: &BigInt, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    if f.is_infinite() {
+        return Some(if f > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        });
+    }
+    let t = f.trunc();
+    let tb = BigInt::from_f64(t)?;
+    Some(b.cmp(&tb).then_with(|| compare_fraction(f, t)))
+}
+
+fn compare_fraction(f: f64, t: f64) -> Ordering {
+    if f > t {
+        Ordering::Less
+    } else if f < t {
+        Ordering::Greater
+    } else {
+        Ordering::Equal
+    }
+}
+
+fn compare_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    // 2^63 is exactly representable, i64::MAX is not.
+    if f >= 9_223_372_036_854_775_808.0 {
+        return Some(Ordering::Less);
+    }
+    if f < -9_223_372_036_854_775_808.0 {
+        return Some(Ordering::Greater);
+    }
+    let t = f.trunc();
+    Some(i.cmp(&(t as i64)).then_with(|| compare_fraction(f, t)))
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Str(l), Value::Str(r)) => l == r,
+            (Value::Function(l), Value::Function(r)) => Rc::ptr_eq(l, r),
+            (Value::Closure(l), Value::Closure(r)) => Rc::ptr_eq(l, r),
+            (Value::Generator(l), Value::Generator(r)) => Rc::ptr_eq(l, r),
+            (Value::Fiber(l), Value::Fiber(r)) => Rc::ptr_eq(l, r),
+            (Value::Builtin(l), Value::Builtin(r)) => l == r,
+            (Value::Class(l), Value::Class(r)) => Rc::ptr_eq(l, r),
+            (Value::Instance(l), Value::Instance(r)) => Rc::ptr_eq(l, r),
+            (Value::BoundMethod(l), Value::BoundMethod(r)) => Rc::ptr_eq(l, r),
+            (Value::Enum(l), Value::Enum(r)) => Rc::ptr_eq(l, r),
+            (Value::Variant(l), Value::Variant(r)) => {
+                Rc::ptr_eq(&l.enum_, &r.enum_) && l.tag == r.tag && l.values == r.values
+            }
+            (Value::Constructor(l), Value::Constructor(r)) => {
+                Rc::ptr_eq(&l.enum_, &r.enum_) && l.tag == r.tag
+            }
+            (Value::Range(l), Value::Range(r)) => l == r,
+            (Value::List(l), Value::List(r)) => Rc::ptr_eq(l, r),
+            (Value::Map(l), Value::Map(r)) => Rc::ptr_eq(l, r),
+            (Value::Pattern(l), Value::Pattern(r)) => Rc::ptr_eq(l, r),
+            (l, r) => matches!(l.compare_numbers(r), Ok(Some(Ordering::Equal))),
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
+            Value::Number(v) if v.is_finite() && v.fract() == 0.0 => write!(f, "{:.1}", v),
             Value::Number(v) => write!(f, "{}", v),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::BigInt(v) => write!(f, "{}", v),
             Value::Bool(v) => write!(f, "{}", v),
             Value::Nil => write!(f, "nil"),
             Value::Str(s) => write!(f, "{}", s),
+            Value::Function(v) => write!(f, "{}", v),
+            Value::Closure(v) => write!(f, "{}", v),
+            Value::Generator(v) => write!(f, "{}", v.borrow()),
+            Value::Fiber(v) => write!(f, "{}", v.borrow()),
+            Value::Builtin(v) => write!(f, "{}", v),
+            Value::Class(v) => write!(f, "{}", v.borrow()),
+            Value::Instance(v) => write!(f, "{}", v.borrow()),
+            Value::BoundMethod(v) => write!(f, "{}", v),
+            Value::Enum(v) => write!(f, "{}", v),
+            Value::Variant(v) => write!(f, "{}", v),
+            Value::Constructor(v) => write!(f, "{}", v),
+            Value::Range(v) => write!(f, "{}", v),
+            Value::List(v) => {
+                let items: Vec<String> = v.borrow().iter().map(Value::to_string).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map(v) => {
+                let entries: Vec<String> = v
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Value::Pattern(v) => write!(f, "<pattern {}>", v),
         }
     }
 }
 
-pub struct ConstArray(Vec<Value>);
+#[derive(Debug, Clone)]
+pub struct ConstArray(Vec<Slot>);
 
 impl ConstArray {
     pub fn new() -> Self {
@@ -112,14 +514,128 @@ impl ConstArray {
     }
 
     pub fn write(&mut self, value: Value) {
-        self.0.push(value)
+        self.0.push(value.into_slot())
+    }
+
+    /// Drops the constants from `len` on, which no code may load any more.
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len)
     }
 }
 
 impl Deref for ConstArray {
-    type Target = Vec<Value>;
+    type Target = Vec<Slot>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_int_float_equality() {
+        assert_eq!(Value::Int(1), Value::Number(1.0));
+        assert_ne!(Value::Int(1), Value::Number(1.5));
+        assert_ne!(Value::Int(1), Value::Str("1".to_string()));
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+    }
+
+    #[test]
+    fn test_compare_numbers() {
+        let big = Value::Int(9_007_199_254_740_993);
+        let float = Value::Number(9_007_199_254_740_992.0);
+        assert_eq!(
+            big.compare_numbers(&float).unwrap(),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(float.compare_numbers(&big).unwrap(), Some(Ordering::Less));
+        assert_eq!(
+            Value::Int(-3)
+                .compare_numbers(&Value::Number(-2.5))
+                .unwrap(),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            Value::Int(i64::MAX)
+                .compare_numbers(&Value::Number(9_223_372_036_854_775_808.0))
+                .unwrap(),
+            Some(Ordering::Less)
+        );
+        assert!(Value::Nil.compare_numbers(&Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn test_integer_op_promotes_and_demotes() {
+        let add: fn(BigInt, BigInt) -> BigInt = |l, r| l + r;
+        let sum = Value::integer_op(&Value::Int(i64::MAX), &Value::Int(1), i64::checked_add, add)
+            .unwrap();
+        assert!(sum.is_bigint());
+        assert_eq!(sum.to_string(), "9223372036854775808");
+
+        let back = Value::integer_op(&sum, &Value::Int(-1), i64::checked_add, add).unwrap();
+        assert_eq!(back.to_int().unwrap(), i64::MAX);
+
+        assert!(
+            Value::integer_op(&Value::Int(1), &Value::Number(1.0), i64::checked_add, add).is_none()
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Value::Int(3).to_string(), "3");
+        assert_eq!(Value::Number(3.0).to_string(), "3.0");
+        assert_eq!(Value::Number(3.5).to_string(), "3.5");
+    }
+
+    #[test]
+    fn test_variants() {
+        let shape = Rc::new(Enum {
+            name: "Shape".to_string(),
+            variants: vec![
+                ("Circle".to_string(), vec!["r".to_string()]),
+                ("Empty".to_string(), Vec::new()),
+            ],
+        });
+        let circle = |r| {
+            Value::Variant(Rc::new(Variant {
+                enum_: shape.clone(),
+                tag: 0,
+                values: vec![Value::Int(r)],
+            }))
+        };
+        assert_eq!(circle(2).to_string(), "Shape.Circle(2)");
+        assert_eq!(shape.member(1).to_string(), "Shape.Empty");
+        assert_eq!(shape.member(0).to_string(), "<fn Shape.Circle>");
+        assert_eq!(circle(2), circle(2));
+        assert_ne!(circle(2), circle(3));
+        assert_eq!(shape.member(1), shape.member(1));
+
+        let pattern = Pattern::Variant(shape.clone(), 0, vec![Pattern::Bind("r".to_string())]);
+        let mut bindings = Vec::new();
+        assert!(pattern.matches(&circle(5), &mut bindings));
+        assert_eq!(bindings, vec![Value::Int(5)]);
+        assert!(!pattern.matches(&shape.member(1), &mut Vec::new()));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let add = |l: &Value, r: &Value| {
+            Value::arithmetic(l, r, i64::checked_add, |l, r| l + r, |l, r| l + r)
+        };
+        assert_eq!(add(&Value::Int(1), &Value::Int(2)).unwrap(), Value::Int(3));
+        assert!(add(&Value::Int(i64::MAX), &Value::Int(1))
+            .unwrap()
+            .is_bigint());
+        assert_eq!(
+            add(&Value::Int(1), &Value::Number(0.5)).unwrap(),
+            Value::Number(1.5)
+        );
+        assert!(add(&Value::Int(1), &Value::Nil).is_err());
+        assert!(Value::Int(i64::MIN).negate().unwrap().is_bigint());
+        assert_eq!(Value::Number(2.0).negate().unwrap(), Value::Number(-2.0));
+        assert!(Value::Str("a".to_string()).negate().is_err());
+    }
+}
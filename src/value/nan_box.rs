@@ -0,0 +1,444 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use super::Value;
+use crate::object::{
+    BoundMethod, Class, Closure, Constructor, Enum, Fiber, Function, Generator, Instance, Pattern,
+    Variant,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+/// Exponent bits, the quiet bit and one more: no float the VM produces has
+/// all of them set once NaNs are canonicalized.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+/// Marks an integer in the low 48 bits of a tagged value.
+const TAG_INT: u64 = 0x0001_0000_0000_0000;
+const PAYLOAD: u64 = 0x0000_ffff_ffff_ffff;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+/// Marks a heap value. Its kind is split between the two bits above the
+/// 48-bit pointer and the three below it, which alignment leaves clear.
+const HEAP: u64 = SIGN_BIT | QNAN;
+const KIND_HIGH: u64 = 0x0003_0000_0000_0000;
+const KIND_LOW: u64 = 0x7;
+const POINTER: u64 = PAYLOAD & !KIND_LOW;
+/// The kind of a pointer to an `Rc<Value>`, for values that hold no `Rc` of
+/// their own.
+const BOXED: u64 = 0;
+
+/// A `Value` packed into 64 bits. Floats are stored as themselves and
+/// everything else in the payload of a quiet NaN: nil and booleans as
+/// constants, integers that fit in 48 bits inline, and any other value as a
+/// tagged pointer, marked by the sign bit. Variants that hold an `Rc` keep
+/// its pointer; strings, big integers, builtins and ranges are moved into
+/// an `Rc<Value>` of their own.
+///
+/// The box owns a strong reference to what it points at. The marker keeps
+/// it `!Send` and `!Sync`, like the `Rc` it stands for.
+pub struct NanBox(u64, PhantomData<Rc<Value>>);
+
+/// Fails to compile if `$ty` implements `$trait`: the second impl of
+/// `Ambiguous` then applies too, and `<$ty as Ambiguous<_>>` has no single
+/// answer.
+macro_rules! assert_not_impl {
+    ($ty:ty, $trait:path) => {
+        const _: fn() = || {
+            trait Ambiguous<A> {
+                fn some_item() {}
+            }
+            impl<T: ?Sized> Ambiguous<()> for T {}
+            struct Invalid;
+            impl<T: ?Sized + $trait> Ambiguous<Invalid> for T {}
+            let _ = <$ty as Ambiguous<_>>::some_item;
+        };
+    };
+}
+
+assert_not_impl!(NanBox, Send);
+assert_not_impl!(NanBox, Sync);
+
+/// Packing and unpacking of the variants that hold an `Rc`, each under its
+/// own kind.
+macro_rules! rc_kinds {
+    ($($kind:literal => $variant:ident($ty:ty),)*) => {
+        impl NanBox {
+            /// Packs `value` if it holds an `Rc`, taking over its reference.
+            fn pack_rc(value: Value) -> Result<NanBox, Value> {
+                match value {
+                    $(Value::$variant(rc) => {
+                        Ok(NanBox::pointer($kind, Rc::into_raw(rc) as u64))
+                    })*
+                    value => Err(value),
+                }
+            }
+
+            /// Rebuilds the `Rc` variant packed with `kind` at `pointer`.
+            ///
+            /// # Safety
+            ///
+            /// The pointer must come from `pack_rc` with the same kind. The
+            /// value owns one strong reference: the caller must own one to
+            /// give it, or never drop it.
+            unsafe fn unpack_rc(kind: u64, pointer: u64) -> Value {
+                match kind {
+                    $($kind => Value::$variant(Rc::from_raw(pointer as *const $ty)),)*
+                    kind => unreachable!("invalid nan box kind {}", kind),
+                }
+            }
+
+            /// Adds a strong reference to the `Rc` packed with `kind` at
+            /// `pointer`, or with `increment` false drops one.
+            ///
+            /// # Safety
+            ///
+            /// As for `unpack_rc`; dropping gives up a reference the caller
+            /// owns.
+            unsafe fn count_rc(kind: u64, pointer: u64, increment: bool) {
+                match kind {
+                    $($kind if increment => Rc::increment_strong_count(pointer as *const $ty),
+                    $kind => Rc::decrement_strong_count(pointer as *const $ty),)*
+                    kind => unreachable!("invalid nan box kind {}", kind),
+                }
+            }
+        }
+    };
+}
+
+rc_kinds! {
+    1 => Function(Function),
+    2 => Closure(Closure),
+    3 => Generator(RefCell<Generator>),
+    4 => Fiber(RefCell<Fiber>),
+    5 => Class(RefCell<Class>),
+    6 => Instance(RefCell<Instance>),
+    7 => BoundMethod(BoundMethod),
+    8 => Enum(Enum),
+    9 => Variant(Variant),
+    10 => Constructor(Constructor),
+    11 => List(RefCell<Vec<Value>>),
+    12 => Map(RefCell<BTreeMap<String, Value>>),
+    13 => Pattern(Pattern),
+}
+
+/// Something held in an `Rc` variant, lent out of a `NanBox` that shares
+/// its reference. It can't outlive the box and is never dropped.
+pub(crate) struct Borrowed<'a, T> {
+    value: ManuallyDrop<T>,
+    boxed: PhantomData<&'a NanBox>,
+}
+
+impl<T> Deref for Borrowed<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl NanBox {
+    fn new(bits: u64) -> Self {
+        NanBox(bits, PhantomData)
+    }
+
+    fn pointer(kind: u64, pointer: u64) -> Self {
+        assert_eq!(pointer & !POINTER, 0, "heap pointer wider than 48 bits");
+        NanBox::new(HEAP | ((kind >> 3) << 48 & KIND_HIGH) | (kind & KIND_LOW) | pointer)
+    }
+
+    fn is_float(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    fn is_inline_int(&self) -> bool {
+        self.0 & (SIGN_BIT | QNAN | TAG_INT) == QNAN | TAG_INT
+    }
+
+    /// The kind and pointer of a heap value.
+    fn heap_pointer(&self) -> Option<(u64, u64)> {
+        if self.0 & HEAP != HEAP {
+            return None;
+        }
+        let kind = ((self.0 & KIND_HIGH) >> 45) | (self.0 & KIND_LOW);
+        Some((kind, self.0 & POINTER))
+    }
+
+    /// The value of a string, big integer, builtin or range.
+    pub(super) fn heap(&self) -> Option<&Value> {
+        match self.heap_pointer()? {
+            // The pointer came from `Rc::into_raw` and this box owns one
+            // strong reference to it, so it lives at least as long as `self`.
+            (BOXED, pointer) => Some(unsafe { &*(pointer as *const Value) }),
+            _ => None,
+        }
+    }
+
+    /// The value of an `Rc` variant, sharing this box's reference. Wrapped
+    /// so it is never dropped.
+    fn rc(&self) -> Option<ManuallyDrop<Value>> {
+        match self.heap_pointer()? {
+            (BOXED, _) => None,
+            (kind, pointer) => Some(ManuallyDrop::new(unsafe {
+                NanBox::unpack_rc(kind, pointer)
+            })),
+        }
+    }
+
+    /// Lends out the `Rc` that `take` finds in the value of an `Rc` variant.
+    pub(super) fn lend<T, E>(&self, take: fn(&Value) -> Result<&T, E>) -> Option<Borrowed<'_, T>> {
+        let value = self.rc()?;
+        let rc = take(&value).ok()?;
+        // A bitwise copy, which like `value` never drops its reference.
+        Some(Borrowed {
+            value: ManuallyDrop::new(unsafe { std::ptr::read(rc) }),
+            boxed: PhantomData,
+        })
+    }
+
+    /// Runs `f` on the unpacked value. Heap values are borrowed; inline ones
+    /// are rebuilt, which is free for everything but the call itself.
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&Value) -> R) -> R {
+        match self.heap_pointer() {
+            Some((BOXED, pointer)) => f(unsafe { &*(pointer as *const Value) }),
+            Some((kind, pointer)) => f(&ManuallyDrop::new(unsafe {
+                NanBox::unpack_rc(kind, pointer)
+            })),
+            None => f(&self.inline()),
+        }
+    }
+
+    pub(crate) fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub(crate) fn is_falsey(&self) -> bool {
+        self.0 == NIL || self.0 == FALSE
+    }
+
+    pub(crate) fn is_integer(&self) -> bool {
+        self.is_inline_int() || self.with(Value::is_integer)
+    }
+
+    /// The integer of an inline `Int`. Integers wider than 48 bits are left
+    /// to the slow path.
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        if self.is_inline_int() {
+            Some(((self.0 << 16) as i64) >> 16)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn into_value(self) -> Value {
+        Value::from(self)
+    }
+
+    pub(crate) fn to_value(&self) -> Value {
+        self.with(Value::clone)
+    }
+
+    fn inline(&self) -> Value {
+        match self.0 {
+            NIL => Value::Nil,
+            FALSE => Value::Bool(false),
+            TRUE => Value::Bool(true),
+            bits if self.is_float() => Value::Number(f64::from_bits(bits)),
+            // Shifting back down sign-extends the 48-bit integer.
+            bits if self.is_inline_int() => Value::Int(((bits << 16) as i64) >> 16),
+            bits => unreachable!("invalid nan box {:#x}", bits),
+        }
+    }
+}
+
+impl From<Value> for NanBox {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => NanBox::new(NIL),
+            Value::Bool(false) => NanBox::new(FALSE),
+            Value::Bool(true) => NanBox::new(TRUE),
+            Value::Number(n) if n.is_nan() => NanBox::new(CANONICAL_NAN),
+            Value::Number(n) => NanBox::new(n.to_bits()),
+            Value::Int(i) if (i << 16) >> 16 == i => {
+                NanBox::new(QNAN | TAG_INT | (i as u64 & PAYLOAD))
+            }
+            value => match NanBox::pack_rc(value) {
+                Ok(boxed) => boxed,
+                Err(value) => NanBox::pointer(BOXED, Rc::into_raw(Rc::new(value)) as u64),
+            },
+        }
+    }
+}
+
+impl From<NanBox> for Value {
+    fn from(boxed: NanBox) -> Self {
+        let (kind, pointer) = match boxed.heap_pointer() {
+            Some(heap) => heap,
+            None => return boxed.inline(),
+        };
+        // Take over the box's reference instead of dropping it.
+        std::mem::forget(boxed);
+        if kind != BOXED {
+            return unsafe { NanBox::unpack_rc(kind, pointer) };
+        }
+        let value = unsafe { Rc::from_raw(pointer as *const Value) };
+        Rc::try_unwrap(value).unwrap_or_else(|value| (*value).clone())
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        match self.heap_pointer() {
+            Some((BOXED, pointer)) => unsafe {
+                Rc::increment_strong_count(pointer as *const Value)
+            },
+            Some((kind, pointer)) => unsafe { NanBox::count_rc(kind, pointer, true) },
+            None => {}
+        }
+        NanBox::new(self.0)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        match self.heap_pointer() {
+            Some((BOXED, pointer)) => unsafe {
+                Rc::decrement_strong_count(pointer as *const Value)
+            },
+            Some((kind, pointer)) => unsafe { NanBox::count_rc(kind, pointer, false) },
+            None => {}
+        }
+    }
+}
+
+impl PartialEq for NanBox {
+    fn eq(&self, other: &NanBox) -> bool {
+        self.with(|l| other.with(|r| l == r))
+    }
+}
+
+impl fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.with(|value| write!(f, "NanBox({:?})", value))
+    }
+}
+
+impl fmt::Display for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.with(|value| write!(f, "{}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigInt;
+
+    #[test]
+    fn test_round_trip() {
+        let values = vec![
+            Value::Nil,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Number(1.5),
+            Value::Number(-0.0),
+            Value::Number(f64::INFINITY),
+            Value::Int(0),
+            Value::Int(-1),
+            Value::Int((1 << 47) - 1),
+            Value::Int(-(1 << 47)),
+            Value::Int(1 << 47),
+            Value::Int(i64::MIN),
+            Value::BigInt(BigInt::from(u64::MAX) * 3),
+            Value::Str("abc".to_string()),
+        ];
+        for value in values {
+            let boxed = NanBox::from(value.clone());
+            assert_eq!(boxed.to_int().ok(), value.to_int().ok());
+            assert_eq!(boxed.is_number(), value.is_number());
+            assert_eq!(Value::from(boxed), value);
+        }
+        assert!(Value::from(NanBox::from(Value::Number(f64::NAN)))
+            .to_number()
+            .unwrap()
+            .is_nan());
+    }
+
+    #[test]
+    fn test_accessors() {
+        assert_eq!(std::mem::size_of::<NanBox>(), 8);
+        let s = NanBox::from(Value::Str("abc".to_string()));
+        assert!(s.is_str());
+        assert_eq!(s.to_str().unwrap(), "abc");
+        assert!(s.to_number().is_err());
+        assert_eq!(NanBox::from(Value::Number(2.0)).to_number().unwrap(), 2.0);
+        assert!(NanBox::from(Value::Bool(true)).into_bool().unwrap());
+        assert_eq!(s.clone(), s);
+        assert_eq!(s.to_string(), "abc");
+        assert_eq!(s.as_str().unwrap(), "abc");
+        assert!(NanBox::from(Value::Int(1)).as_bigint().is_err());
+    }
+
+    #[test]
+    fn test_slot_checks() {
+        assert!(NanBox::from(Value::Nil).is_nil());
+        assert!(NanBox::from(Value::Nil).is_falsey());
+        assert!(NanBox::from(Value::Bool(false)).is_falsey());
+        assert!(!NanBox::from(Value::Int(0)).is_falsey());
+        assert!(!NanBox::from(Value::Number(0.0)).is_nil());
+        assert_eq!(NanBox::from(Value::Int(-5)).as_i64(), Some(-5));
+        assert_eq!(NanBox::from(Value::Int(i64::MAX)).as_i64(), None);
+        assert!(NanBox::from(Value::Int(i64::MAX)).is_integer());
+        assert_eq!(NanBox::from(Value::Number(1.0)).as_i64(), None);
+    }
+
+    #[test]
+    fn test_reference_counts() {
+        let closure = Rc::new(crate::object::Closure {
+            function: Rc::new(crate::object::Function::new(None)),
+            upvalues: Vec::new(),
+        });
+        let boxed = NanBox::from(Value::Closure(closure.clone()));
+        assert_eq!(Rc::strong_count(&closure), 2);
+        let copy = boxed.clone();
+        drop(boxed);
+        assert!(Rc::ptr_eq(&copy.to_closure().unwrap(), &closure));
+        drop(copy);
+        assert_eq!(Rc::strong_count(&closure), 1);
+    }
+
+    #[test]
+    fn test_rc_variants() {
+        let list = Rc::new(RefCell::new(vec![Value::Int(1)]));
+        let boxed = NanBox::from(Value::List(list.clone()));
+        // The box holds the list's own pointer, not a copy of it.
+        assert_eq!(Rc::strong_count(&list), 2);
+        assert!(boxed.heap().is_none());
+        assert!(boxed.is_list());
+        assert!(!boxed.is_map());
+        assert!(boxed.as_map().is_err());
+        {
+            let lent = boxed.as_list().unwrap();
+            assert!(Rc::ptr_eq(&lent, &list));
+            assert_eq!(Rc::strong_count(&list), 2);
+        }
+        assert_eq!(Rc::strong_count(&list), 2);
+        assert_eq!(boxed.to_string(), "[1]");
+
+        let map = NanBox::from(Value::Map(Rc::new(RefCell::new(BTreeMap::new()))));
+        assert!(map.is_map());
+        assert!(map.as_list().is_err());
+
+        let value = Value::from(boxed);
+        assert_eq!(Rc::strong_count(&list), 2);
+        assert!(Rc::ptr_eq(&value.into_list().unwrap(), &list));
+        assert_eq!(Rc::strong_count(&list), 1);
+    }
+}
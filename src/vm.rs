@@ -1,178 +1,2557 @@
-use crate::chunk::{read_u24, Chunk, OpCode};
-use crate::compiler::Compiler;
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler::{CompileOptions, Compiler, GlobalBindings};
+#[cfg(feature = "debug-trace-execution")]
 use crate::debug::{disassemble_instruction, print_value};
-use crate::error::{self, Result};
-use crate::value::Value;
+use crate::error::{self, Error, Result};
+use crate::object::{
+    BoundMethod, Builtin, Class, Closure, Fiber, FiberState, Function, Generator, GeneratorState,
+    Instance, MethodKind, Range, Upvalue, Variant,
+};
+use crate::value::{Slot, Value, ValueTypeError};
 use snafu::{OptionExt, ResultExt};
-use std::convert::TryInto;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::rc::Rc;
 
-const STACK_MAX: usize = 100;
+/// Default limit on the number of values on a fiber's stack. Every call frame
+/// keeps its callee on the stack until it returns, so this also limits how
+/// deep calls can nest.
+const STACK_MAX: usize = 16 * 1024;
+const STACK_INITIAL: usize = 256;
+const FRAMES_INITIAL: usize = 64;
 
-pub struct VM {
-    pub chunk: Chunk,
+#[derive(Debug)]
+pub(crate) struct CallFrame {
+    /// `None` for the top-level script, whose code is `VM::chunk`.
+    closure: Option<Rc<Closure>>,
     ip: usize,
-    stack: Vec<Value>,
+    /// Index of the frame's first stack slot, which holds the callee.
+    slots: usize,
+    /// Which parameters the caller passed, for functions with default values.
+    /// Empty when every parameter is required.
+    args_passed: Vec<bool>,
+    /// Set when the frame runs the body of a generator, which `OpYield` and
+    /// `OpReturn` hand control back from.
+    generator: Option<Rc<RefCell<Generator>>>,
+    on_return: OnReturn,
+    /// Frames that tail calls replaced with this one, for stack traces.
+    elided: usize,
+}
+
+/// What the caller does with a frame's return value.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum OnReturn {
+    Push,
+    /// A generator resumed by a for-in loop. Once it returns, the loop
+    /// continues at this ip in the caller.
+    ExitLoop(usize),
+    /// A getter invoked as `receiver.name(args)`. Its result is called with
+    /// the arguments below it.
+    Call(usize),
+    /// The `iterate()` method of an instance a for-in loop goes over, whose
+    /// iterable is in `slot`. Its result becomes the loop's iterator and the
+    /// `OpForIter` at `ip` runs again.
+    Iterate {
+        slot: usize,
+        ip: usize,
+    },
+    /// An iterator's `next()` called by a for-in loop. A nil result ends the
+    /// loop, which continues at this ip in the caller.
+    Next(usize),
+    /// An instance's `__str()` called by `print`, which prints its result.
+    Print,
+}
+
+/// The code a frame runs, held apart from the VM so the dispatch loop can
+/// read it while changing the VM.
+#[derive(Clone)]
+enum Code {
+    Script(Rc<Chunk>),
+    Closure(Rc<Closure>),
+}
+
+impl Code {
+    fn chunk(&self) -> &Chunk {
+        match self {
+            Code::Script(chunk) => chunk,
+            Code::Closure(closure) => &closure.function.chunk,
+        }
+    }
+
+    fn upvalue(&self, index: usize) -> Result<&Rc<RefCell<Upvalue>>> {
+        match self {
+            Code::Closure(closure) => {
+                closure
+                    .upvalues
+                    .get(index)
+                    .with_context(|| error::NoOpCodeError {
+                        msg: format!("upvalue {}", index),
+                    })
+            }
+            Code::Script(_) => error::NoOpCodeError {
+                msg: "upvalue outside function",
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Reads the byte at `ip` in `code` and moves past it. The code must have
+/// passed `Chunk::verify`, and `ip` must be inside an instruction it checked.
+fn next_byte(code: &[u8], ip: &mut usize) -> u8 {
+    debug_assert!(*ip < code.len());
+    // SAFETY: verified code holds all of every instruction's operands, and
+    // the dispatch loop only starts at instructions: where a frame starts, after
+    // the one before or where a checked jump lands.
+    let byte = unsafe { *code.get_unchecked(*ip) };
+    *ip += 1;
+    byte
+}
+
+fn next_short(code: &[u8], ip: &mut usize) -> usize {
+    (next_byte(code, ip) as usize) << 8 | next_byte(code, ip) as usize
 }
 
-impl VM {
-    pub fn new() -> Self {
-        VM {
-            chunk: Chunk::new(),
-            ip: 0,
-            stack: Vec::with_capacity(STACK_MAX),
+/// Reads a constant index operand, one byte or three for `long` ops.
+fn next_index(code: &[u8], ip: &mut usize, long: bool) -> usize {
+    if long {
+        (next_byte(code, ip) as usize) << 16
+            | (next_byte(code, ip) as usize) << 8
+            | next_byte(code, ip) as usize
+    } else {
+        next_byte(code, ip) as usize
+    }
+}
+
+/// Reads the opcode at `ip` in verified code.
+fn next_op(code: &[u8], ip: &mut usize) -> OpCode {
+    let byte = next_byte(code, ip);
+    debug_assert!(OpCode::decode(byte).is_some());
+    // SAFETY: `Chunk::verify` checked that every instruction starts with a
+    // known opcode.
+    unsafe { OpCode::decode(byte).unwrap_unchecked() }
+}
+
+/// The constant at `index` in verified `chunk`.
+fn constant(chunk: &Chunk, index: usize) -> &Slot {
+    debug_assert!(index < chunk.constants.len());
+    // SAFETY: `Chunk::verify` checked every constant operand.
+    unsafe { chunk.constants.get_unchecked(index) }
+}
+
+/// The value a parked upvalue points at on a suspended generator's stack.
+fn parked_slot(stack: &[Slot], index: usize) -> Result<&Slot> {
+    stack.get(index).with_context(|| error::NoOpCodeError {
+        msg: format!("parked slot {}", index),
+    })
+}
+
+fn parked_slot_mut(stack: &mut [Slot], index: usize) -> Result<&mut Slot> {
+    stack.get_mut(index).with_context(|| error::NoOpCodeError {
+        msg: format!("parked slot {}", index),
+    })
+}
+
+pub struct VM {
+    /// The script, shared with the dispatch loop while it runs.
+    pub chunk: Rc<Chunk>,
+    frames: Vec<CallFrame>,
+    stack: Vec<Slot>,
+    /// Most values a fiber's stack may hold before a stack overflow.
+    stack_max: usize,
+    globals: HashMap<String, Slot>,
+    global_bindings: GlobalBindings,
+    /// Upvalues still pointing at live stack slots.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// The running fiber. `stack`, `frames` and `open_upvalues` belong to it.
+    fiber: Rc<RefCell<Fiber>>,
+    options: CompileOptions,
+    /// Where `print` writes.
+    out: Box<dyn Write>,
+    /// The stack trace of the last uncaught runtime error, innermost call
+    /// first.
+    trace: Vec<String>,
+}
+
+impl VM {
+    pub fn with_options(options: CompileOptions) -> Self {
+        let mut globals = HashMap::new();
+        globals.insert(
+            "Fiber".to_string(),
+            Value::Builtin(Builtin::Fiber).into_slot(),
+        );
+        VM {
+            chunk: Rc::new(Chunk::new()),
+            frames: Vec::with_capacity(FRAMES_INITIAL),
+            stack: Vec::with_capacity(STACK_INITIAL),
+            stack_max: STACK_MAX,
+            globals,
+            global_bindings: GlobalBindings::default(),
+            open_upvalues: Vec::new(),
+            fiber: Rc::new(RefCell::new(Fiber::new(Vec::new(), FiberState::Running))),
+            options,
+            out: Box::new(io::stdout()),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn set_stack_max(&mut self, stack_max: usize) {
+        self.stack_max = stack_max;
+    }
+
+    pub fn interpret_source(&mut self, source: &str) -> Result<()> {
+        // The chunk keeps growing across REPL lines; start at the new code.
+        let chunk = Rc::make_mut(&mut self.chunk);
+        let ip = chunk.len();
+        let bindings = self.global_bindings.clone();
+        let compiled = Compiler::new(
+            source.as_bytes(),
+            chunk,
+            &mut self.global_bindings,
+            self.options,
+        )
+        .compile();
+        // Checked once here, so the dispatch loop can read it unchecked.
+        let compiled = compiled.and_then(|()| self.chunk.verify(ip, 0));
+        if let Err(err) = compiled {
+            // Forget the declarations of code that never runs.
+            self.global_bindings = bindings;
+            Rc::make_mut(&mut self.chunk).truncate(ip);
+            return Err(err);
+        }
+        self.frames.push(CallFrame {
+            closure: None,
+            ip,
+            slots: 0,
+            args_passed: Vec::new(),
+            generator: None,
+            on_return: OnReturn::Push,
+            elided: 0,
+        });
+        let ret = self.run();
+        if ret.is_err() {
+            self.trace = self.stack_trace();
+            while self.finish_fiber().is_some() {
+                let trace = self.stack_trace();
+                self.trace.extend(trace);
+            }
+            self.abandon_stack();
+        }
+        ret
+    }
+
+    fn frame(&self) -> Result<&CallFrame> {
+        self.frames.last().with_context(|| error::NoOpCodeError {
+            msg: "no call frame",
+        })
+    }
+
+    fn frame_mut(&mut self) -> Result<&mut CallFrame> {
+        self.frames
+            .last_mut()
+            .with_context(|| error::NoOpCodeError {
+                msg: "no call frame",
+            })
+    }
+
+    fn pop_frame(&mut self) -> Result<CallFrame> {
+        self.frames.pop().with_context(|| error::NoOpCodeError {
+            msg: "no call frame",
+        })
+    }
+
+    /// The current frame's code, ip and first slot.
+    fn frame_code(&self) -> Result<(Code, usize, usize)> {
+        let frame = self.frame()?;
+        let code = match &frame.closure {
+            Some(closure) => Code::Closure(closure.clone()),
+            None => Code::Script(self.chunk.clone()),
+        };
+        Ok((code, frame.ip, frame.slots))
+    }
+
+    fn current_chunk(&self) -> Result<&Chunk> {
+        match &self.frame()?.closure {
+            Some(closure) => Ok(&closure.function.chunk),
+            None => Ok(&self.chunk),
+        }
+    }
+
+    /// The string constant at `index` in `chunk`, borrowed rather than cloned.
+    fn constant_str<'c>(&self, chunk: &'c Chunk, index: usize) -> Result<&'c str> {
+        constant(chunk, index)
+            .as_str()
+            .map(String::as_str)
+            .with_context(|| error::TypeError {
+                msg: "variable name",
+                line: self.current_line(),
+            })
+    }
+
+    pub fn push(&mut self, value: Value) -> Result<()> {
+        self.push_slot(value.into_slot())
+    }
+
+    fn push_slot(&mut self, slot: Slot) -> Result<()> {
+        self.reserve(1)?;
+        self.stack.push(slot);
+        Ok(())
+    }
+
+    /// Fails with a stack overflow unless `count` more values fit on the stack.
+    fn reserve(&mut self, count: usize) -> Result<()> {
+        if self.stack.len() + count > self.stack_max {
+            return error::RuntimeError {
+                msg: "Stack overflow.",
+                line: self.current_line(),
+            }
+            .fail();
+        }
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<Value> {
+        self.pop_slot().map(Slot::into_value)
+    }
+
+    fn pop_slot(&mut self) -> Result<Slot> {
+        self.stack
+            .pop()
+            .with_context(|| error::NoOpCodeError { msg: "pop error" })
+    }
+
+    pub(crate) fn peek(&self, index: usize) -> Result<&Slot> {
+        let slot = self.slot_from_top(index)?;
+        self.slot(slot)
+    }
+
+    /// Index of the first of the top `count` values on the stack.
+    fn first_of_top(&self, count: usize) -> Result<usize> {
+        self.stack
+            .len()
+            .checked_sub(count)
+            .with_context(|| error::NoOpCodeError {
+                msg: format!("take {}", count),
+            })
+    }
+
+    /// Index of the value `distance` slots below the top of the stack.
+    fn slot_from_top(&self, distance: usize) -> Result<usize> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .with_context(|| error::NoOpCodeError {
+                msg: format!("peek {}", distance),
+            })
+    }
+
+    fn slot(&self, slot: usize) -> Result<&Slot> {
+        self.stack.get(slot).with_context(|| error::NoOpCodeError {
+            msg: format!("stack slot {}", slot),
+        })
+    }
+
+    /// Takes the values from `slot` up off the stack.
+    fn take_values(&mut self, slot: usize) -> Vec<Value> {
+        self.stack
+            .split_off(slot)
+            .into_iter()
+            .map(Slot::into_value)
+            .collect()
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> Result<&mut Slot> {
+        self.stack
+            .get_mut(slot)
+            .with_context(|| error::NoOpCodeError {
+                msg: format!("stack slot {}", slot),
+            })
+    }
+
+    /// Takes the stack trace of the last uncaught runtime error.
+    pub fn take_trace(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.trace)
+    }
+
+    /// One line per frame of the running fiber, innermost first.
+    fn stack_trace(&self) -> Vec<String> {
+        let mut trace = Vec::new();
+        for frame in self.frames.iter().rev() {
+            let (chunk, name) = match &frame.closure {
+                Some(closure) => (
+                    &closure.function.chunk,
+                    match &closure.function.name {
+                        Some(name) => format!("{}()", name),
+                        None => "<fn>()".to_string(),
+                    },
+                ),
+                None => (&*self.chunk, "script".to_string()),
+            };
+            let line = chunk.lines.get(frame.ip.saturating_sub(1));
+            trace.push(format!("[line {}] in {}", line, name));
+            if frame.elided > 0 {
+                let frames = match frame.elided {
+                    1 => "1 frame".to_string(),
+                    n => format!("{} frames", n),
+                };
+                trace.push(format!("... {} elided by tail calls", frames));
+            }
+        }
+        trace
+    }
+
+    /// Line of the instruction currently being executed.
+    fn current_line(&self) -> usize {
+        match (self.current_chunk(), self.frame()) {
+            (Ok(chunk), Ok(frame)) => chunk.lines.get(frame.ip.saturating_sub(1)) as usize,
+            _ => 0,
+        }
+    }
+
+    /// Finds the closure to run for a call with `arg_count` arguments. Bound
+    /// methods and classes put the receiver in the callee's slot; a class
+    /// without `init` needs no closure.
+    fn resolve_callee(&mut self, callee: Value, arg_count: usize) -> Result<Option<Rc<Closure>>> {
+        let slot = self.slot_from_top(arg_count)?;
+        match callee {
+            Value::Closure(closure) => Ok(Some(closure)),
+            Value::BoundMethod(bound) => {
+                *self.slot_mut(slot)? = bound.receiver.clone().into_slot();
+                Ok(Some(bound.method.clone()))
+            }
+            Value::Constructor(constructor) => {
+                if arg_count != constructor.arity() {
+                    return error::RuntimeError {
+                        msg: format!(
+                            "Expected {} arguments but got {}.",
+                            constructor.arity(),
+                            arg_count
+                        ),
+                        line: self.current_line(),
+                    }
+                    .fail();
+                }
+                let values = self.take_values(slot + 1);
+                *self.slot_mut(slot)? = Value::Variant(Rc::new(Variant {
+                    enum_: constructor.enum_.clone(),
+                    tag: constructor.tag,
+                    values,
+                }))
+                .into_slot();
+                Ok(None)
+            }
+            Value::Class(class) if class.borrow().is_trait => error::RuntimeError {
+                msg: format!("Can't instantiate trait {}.", class.borrow().name),
+                line: self.current_line(),
+            }
+            .fail(),
+            Value::Class(class) => {
+                let instance = Instance::new(class.clone());
+                let init = instance.find_method("init");
+                *self.slot_mut(slot)? =
+                    Value::Instance(Rc::new(RefCell::new(instance))).into_slot();
+                if init.is_none() && arg_count != 0 {
+                    return error::RuntimeError {
+                        msg: format!("Expected 0 arguments but got {}.", arg_count),
+                        line: self.current_line(),
+                    }
+                    .fail();
+                }
+                Ok(init)
+            }
+            _ => error::RuntimeError {
+                msg: "Can only call functions and classes.",
+                line: self.current_line(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Copies the methods of the `count` traits on top of the stack into the
+    /// class beneath them, recording names that more than one trait provides.
+    fn use_traits(&mut self, count: usize) -> Result<()> {
+        let start = self.slot_from_top(count)? + 1;
+        let mut traits = Vec::with_capacity(count);
+        for value in self.take_values(start) {
+            match value {
+                Value::Class(class) if class.borrow().is_trait => traits.push(class),
+                _ => {
+                    return error::RuntimeError {
+                        msg: format!("Can only use traits with 'with', not {}.", value),
+                        line: self.current_line(),
+                    }
+                    .fail()
+                }
+            }
+        }
+        let class = self.pop()?.into_class().with_context(|| error::TypeError {
+            msg: "class",
+            line: self.current_line(),
+        })?;
+        let mut class = class.borrow_mut();
+
+        for kind in &MethodKind::ALL {
+            let mut providers: HashMap<String, Vec<String>> = HashMap::new();
+            for trait_ in &traits {
+                let trait_ = trait_.borrow();
+                for (name, method) in trait_.table(*kind) {
+                    providers
+                        .entry(name.clone())
+                        .or_default()
+                        .push(trait_.name.clone());
+                    class.table_mut(*kind).insert(name.clone(), method.clone());
+                }
+            }
+            let mut providers: Vec<_> = providers.into_iter().collect();
+            providers.sort();
+            for (name, owners) in providers {
+                if owners.len() > 1 {
+                    class.conflicts.push((*kind, name, owners.join(" and ")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arity_error(&self, function: &Function, arg_count: usize) -> Result<()> {
+        let expected = if function.has_rest {
+            format!("at least {}", function.required)
+        } else if function.required == function.arity {
+            function.arity.to_string()
+        } else {
+            format!("{} to {}", function.required, function.arity)
+        };
+        error::RuntimeError {
+            msg: format!("Expected {} arguments but got {}.", expected, arg_count),
+            line: self.current_line(),
+        }
+        .fail()
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        match self.resolve_callee(callee, arg_count)? {
+            Some(closure) => self.call_closure(closure, arg_count),
+            None => Ok(()),
+        }
+    }
+
+    fn call_closure(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<()> {
+        let function = &closure.function;
+        if arg_count < function.required || (arg_count > function.arity && !function.has_rest) {
+            return self.arity_error(function, arg_count);
+        }
+
+        let args_passed = if function.required == function.arity {
+            Vec::new()
+        } else {
+            (0..function.arity).map(|i| i < arg_count).collect()
+        };
+        for _ in arg_count..function.arity {
+            self.push(Value::Nil)?;
+        }
+        if function.has_rest {
+            let surplus = arg_count.saturating_sub(function.arity);
+            let rest = self.take_values(self.first_of_top(surplus)?);
+            self.push(Value::new_list(rest))?;
+        }
+        self.push_frame(closure, args_passed)
+    }
+
+    /// Calls `callee` and, if it got a frame, drops the calling frame so that
+    /// the callee returns straight to its caller. Frames of the script,
+    /// generators and calls whose result the VM handles itself are kept.
+    fn tail_call(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        let depth = self.frames.len();
+        self.call_value(callee, arg_count)?;
+        if self.frames.len() == depth {
+            return Ok(());
+        }
+        let caller = match depth.checked_sub(1).and_then(|i| self.frames.get(i)) {
+            Some(caller) => caller,
+            None => return Ok(()),
+        };
+        if caller.closure.is_none()
+            || caller.generator.is_some()
+            || caller.on_return != OnReturn::Push
+        {
+            return Ok(());
+        }
+        let slots = caller.slots;
+        let elided = caller.elided + 1;
+        self.close_upvalues(slots)?;
+        let mut frame = self.pop_frame()?;
+        self.stack.drain(slots..frame.slots);
+        frame.slots = slots;
+        frame.elided = elided;
+        *self.frame_mut()? = frame;
+        Ok(())
+    }
+
+    /// Calls with `name: value` arguments. The stack holds the callee, the
+    /// positional arguments and then the named ones in `names` order; they are
+    /// rearranged into parameter order before the call.
+    fn call_named(&mut self, positional: usize, names: Vec<String>) -> Result<()> {
+        let callee = self.peek(positional + names.len())?.to_value();
+        let closure = match self.resolve_callee(callee, positional + names.len())? {
+            Some(closure) => closure,
+            None => return Ok(()),
+        };
+        let function = closure.function.clone();
+        if positional > function.arity && !function.has_rest {
+            return self.arity_error(&function, positional + names.len());
+        }
+        let surplus = positional.saturating_sub(function.arity);
+
+        let values = self.take_values(self.slot_from_top(positional + names.len())? + 1);
+        let mut args: Vec<Option<Value>> = vec![None; function.arity];
+        let mut values = values.into_iter();
+        for (arg, value) in args
+            .iter_mut()
+            .zip(values.by_ref().take(positional - surplus))
+        {
+            *arg = Some(value);
+        }
+        let rest: Vec<Value> = values.by_ref().take(surplus).collect();
+        for (name, value) in names.iter().zip(values) {
+            let index = match function.params.iter().position(|param| param == name) {
+                Some(index) => index,
+                None => {
+                    return error::RuntimeError {
+                        msg: format!("{} has no parameter named '{}'.", function, name),
+                        line: self.current_line(),
+                    }
+                    .fail()
+                }
+            };
+            if args[index].is_some() {
+                return error::RuntimeError {
+                    msg: format!("Got more than one value for parameter '{}'.", name),
+                    line: self.current_line(),
+                }
+                .fail();
+            }
+            args[index] = Some(value);
+        }
+        if let Some(missing) = (0..function.required).find(|&i| args[i].is_none()) {
+            return error::RuntimeError {
+                msg: format!(
+                    "Missing argument for parameter '{}'.",
+                    function.params[missing]
+                ),
+                line: self.current_line(),
+            }
+            .fail();
+        }
+
+        let args_passed = args.iter().map(Option::is_some).collect();
+        for arg in args {
+            self.push(arg.unwrap_or(Value::Nil))?;
+        }
+        if function.has_rest {
+            self.push(Value::new_list(rest))?;
+        }
+        self.push_frame(closure, args_passed)
+    }
+
+    /// Pushes a frame for `closure`, whose arguments are already on the stack.
+    /// Generator functions don't run yet; their slots are moved into a new
+    /// generator instead, which replaces the callee on the stack.
+    fn push_frame(&mut self, closure: Rc<Closure>, args_passed: Vec<bool>) -> Result<()> {
+        let slots = self.slot_from_top(closure.function.param_slots())?;
+        if closure.function.is_generator {
+            let stack = self.stack.split_off(slots);
+            self.push(Value::Generator(Rc::new(RefCell::new(Generator {
+                closure,
+                stack: Rc::new(RefCell::new(stack)),
+                upvalues: Vec::new(),
+                ip: 0,
+                args_passed,
+                state: GeneratorState::Suspended,
+            }))))?;
+            return Ok(());
+        }
+        self.frames.push(CallFrame {
+            closure: Some(closure),
+            ip: 0,
+            slots,
+            args_passed,
+            generator: None,
+            on_return: OnReturn::Push,
+            elided: 0,
+        });
+        Ok(())
+    }
+
+    /// Moves a suspended generator's slots back onto the stack and continues
+    /// its frame. The next `OpYield` or `OpReturn` of that frame pushes the
+    /// result of `next()`; a finished generator returns `nil`, or leaves the
+    /// for-in loop driving it.
+    fn resume_generator(
+        &mut self,
+        generator: Rc<RefCell<Generator>>,
+        on_return: OnReturn,
+    ) -> Result<()> {
+        let mut state = generator.borrow_mut();
+        match state.state {
+            GeneratorState::Done => {
+                drop(state);
+                match on_return {
+                    OnReturn::ExitLoop(ip) => self.frame_mut()?.ip = ip,
+                    _ => self.push(Value::Nil)?,
+                }
+                return Ok(());
+            }
+            GeneratorState::Running => {
+                return error::RuntimeError {
+                    msg: "Generator is already running.",
+                    line: self.current_line(),
+                }
+                .fail()
+            }
+            GeneratorState::Suspended => {}
+        }
+        self.reserve(state.stack.borrow().len())?;
+        let slots = self.stack.len();
+        self.stack.append(&mut state.stack.borrow_mut());
+        let upvalues = std::mem::take(&mut state.upvalues);
+        self.reopen_upvalues(upvalues, slots);
+        state.state = GeneratorState::Running;
+        let frame = CallFrame {
+            closure: Some(state.closure.clone()),
+            ip: state.ip,
+            slots,
+            args_passed: std::mem::take(&mut state.args_passed),
+            generator: None,
+            on_return,
+            elided: 0,
+        };
+        drop(state);
+        self.frames.push(CallFrame {
+            generator: Some(generator),
+            ..frame
+        });
+        Ok(())
+    }
+
+    /// Saves the slots of the generator `frame`, which was just popped, and
+    /// parks the upvalues pointing into them until it resumes.
+    fn suspend_generator(&mut self, frame: CallFrame) -> Result<()> {
+        let generator = frame
+            .generator
+            .clone()
+            .with_context(|| error::NoOpCodeError {
+                msg: "yield outside generator",
+            })?;
+        let mut state = generator.borrow_mut();
+        let stack = state.stack.clone();
+        state.upvalues = self.park_upvalues(frame.slots, &stack);
+        stack.borrow_mut().extend(self.stack.drain(frame.slots..));
+        state.ip = frame.ip;
+        state.args_passed = frame.args_passed;
+        state.state = GeneratorState::Suspended;
+        Ok(())
+    }
+
+    /// Parks the open upvalues pointing at `first` or above in `stack`, which
+    /// is about to receive the stack slots from `first` on.
+    fn park_upvalues(
+        &mut self,
+        first: usize,
+        stack: &Rc<RefCell<Vec<Slot>>>,
+    ) -> Vec<Rc<RefCell<Upvalue>>> {
+        let mut parked = Vec::new();
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) if slot >= first => slot,
+                _ => return true,
+            };
+            *upvalue.borrow_mut() = Upvalue::Parked(stack.clone(), slot - first);
+            parked.push(upvalue.clone());
+            false
+        });
+        parked
+    }
+
+    /// Reopens parked upvalues once their slots are back on the stack at
+    /// `first`.
+    fn reopen_upvalues(&mut self, upvalues: Vec<Rc<RefCell<Upvalue>>>, first: usize) {
+        for upvalue in upvalues {
+            let index = match *upvalue.borrow() {
+                Upvalue::Parked(_, index) => index,
+                _ => continue,
+            };
+            *upvalue.borrow_mut() = Upvalue::Open(first + index);
+            self.open_upvalues.push(upvalue);
+        }
+    }
+
+    /// Stores the running fiber's stack and frames in its object.
+    fn switch_out(&mut self) {
+        let fiber = self.fiber.clone();
+        let mut fiber = fiber.borrow_mut();
+        let stack = fiber.stack.clone();
+        fiber.upvalues = self.park_upvalues(0, &stack);
+        *stack.borrow_mut() = std::mem::take(&mut self.stack);
+        fiber.frames = std::mem::take(&mut self.frames);
+    }
+
+    /// Makes `fiber` the running one. The current fiber must be switched out.
+    fn switch_in(&mut self, fiber: Rc<RefCell<Fiber>>) {
+        {
+            let mut state = fiber.borrow_mut();
+            self.stack = std::mem::take(&mut *state.stack.borrow_mut());
+            self.frames = std::mem::take(&mut state.frames);
+            let upvalues = std::mem::take(&mut state.upvalues);
+            self.reopen_upvalues(upvalues, 0);
+        }
+        self.fiber = fiber;
+    }
+
+    /// Transfers control to `fiber`, passing `value` to its function on the
+    /// first call or as the result of its `Fiber.yield()` afterwards.
+    fn call_fiber(&mut self, fiber: Rc<RefCell<Fiber>>, value: Value, is_try: bool) -> Result<()> {
+        let state = fiber.borrow().state;
+        let msg = match state {
+            FiberState::Done => Some("Can't call a finished fiber."),
+            FiberState::Running => Some("Fiber has already been called."),
+            FiberState::New | FiberState::Suspended => None,
+        };
+        if let Some(msg) = msg {
+            return error::RuntimeError {
+                msg,
+                line: self.current_line(),
+            }
+            .fail();
+        }
+
+        self.switch_out();
+        {
+            let mut target = fiber.borrow_mut();
+            target.caller = Some(self.fiber.clone());
+            target.is_try = is_try;
+            target.state = FiberState::Running;
+        }
+        self.switch_in(fiber);
+        if state == FiberState::Suspended {
+            self.push(value)?;
+            return Ok(());
+        }
+        let closure = match self.peek(0)?.to_closure() {
+            Ok(closure) => closure,
+            Err(_) => {
+                return error::RuntimeError {
+                    msg: "A fiber can only run a function.",
+                    line: self.current_line(),
+                }
+                .fail()
+            }
+        };
+        let arg_count = closure.function.arity;
+        if arg_count == 1 {
+            self.push(value)?;
+        }
+        self.push_frame(closure, Vec::new())
+    }
+
+    /// Suspends the running fiber and passes `value` back to its caller.
+    fn yield_fiber(&mut self, value: Value) -> Result<()> {
+        let caller = match self.fiber.borrow_mut().caller.take() {
+            Some(caller) => caller,
+            None => {
+                return error::RuntimeError {
+                    msg: "Can't yield from the main fiber.",
+                    line: self.current_line(),
+                }
+                .fail()
+            }
+        };
+        self.fiber.borrow_mut().state = FiberState::Suspended;
+        self.switch_out();
+        self.switch_in(caller);
+        self.push(value)?;
+        Ok(())
+    }
+
+    /// Drops everything on the running fiber's stack, closing its upvalues.
+    fn abandon_stack(&mut self) {
+        for frame in &self.frames {
+            if let Some(generator) = &frame.generator {
+                generator.borrow_mut().state = GeneratorState::Done;
+            }
+        }
+        // The stack is dropped either way; an upvalue past its end has
+        // nothing to close over.
+        self.close_upvalues(0).ok();
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    /// Ends the running fiber and returns control to its caller, if any.
+    fn finish_fiber(&mut self) -> Option<Rc<RefCell<Fiber>>> {
+        let caller = self.fiber.borrow_mut().caller.take()?;
+        self.abandon_stack();
+        self.fiber.borrow_mut().state = FiberState::Done;
+        self.switch_out();
+        self.switch_in(caller);
+        Some(self.fiber.clone())
+    }
+
+    /// Unwinds fibers up to the nearest one started with `try()` and makes the
+    /// error message the result of that call. `false` if no fiber catches it.
+    fn catch_error(&mut self, err: &Error) -> bool {
+        let mut fiber = self.fiber.clone();
+        loop {
+            let (is_try, caller) = {
+                let state = fiber.borrow();
+                (state.is_try, state.caller.clone())
+            };
+            match caller {
+                None => return false,
+                Some(_) if is_try => break,
+                Some(caller) => fiber = caller,
+            }
+        }
+        loop {
+            let failed = self.fiber.clone();
+            self.finish_fiber();
+            if Rc::ptr_eq(&failed, &fiber) {
+                break;
+            }
+        }
+        self.push(err.to_string().into()).is_ok()
+    }
+
+    /// Checks the argument count of a built-in method taking up to `max`
+    /// optional arguments.
+    fn check_method_arity(&self, arg_count: usize, max: usize) -> Result<()> {
+        if arg_count <= max {
+            return Ok(());
+        }
+        let expected = if max == 0 {
+            "0".to_string()
+        } else {
+            format!("0 to {}", max)
+        };
+        error::RuntimeError {
+            msg: format!("Expected {} arguments but got {}.", expected, arg_count),
+            line: self.current_line(),
+        }
+        .fail()
+    }
+
+    /// Runs `getter` on `receiver` and calls its result with the `arg_count`
+    /// arguments on the stack.
+    fn call_getter_result(
+        &mut self,
+        getter: Rc<Closure>,
+        receiver: Value,
+        arg_count: usize,
+    ) -> Result<()> {
+        let depth = self.frames.len();
+        self.push(receiver)?;
+        self.call_closure(getter, 0)?;
+        if self.frames.len() > depth {
+            self.frame_mut()?.on_return = OnReturn::Call(arg_count);
+            return Ok(());
+        }
+        // A generator getter returned its generator without running.
+        let callee = self.pop_slot()?;
+        let slot = self.slot_from_top(arg_count)?;
+        *self.slot_mut(slot)? = callee.clone();
+        self.call_value(callee.into_value(), arg_count)
+    }
+
+    fn get_property(&self, receiver: &Value, name: &str) -> Result<Value> {
+        if let Value::Class(class) = receiver {
+            if let Some(method) = class.borrow().static_methods.get(name) {
+                return Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                    receiver: receiver.clone(),
+                    method: method.clone(),
+                })));
+            }
+        }
+        if let Value::Instance(instance) = receiver {
+            let instance = instance.borrow();
+            if let Some(value) = instance.fields.get(name) {
+                return Ok(value.clone());
+            }
+            if let Some(method) = instance.find_method(name) {
+                return Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                    receiver: receiver.clone(),
+                    method,
+                })));
+            }
+        }
+        if let Value::Enum(enum_) = receiver {
+            if let Some(tag) = enum_.variant(name) {
+                return Ok(enum_.member(tag));
+            }
+        }
+        if let Value::Variant(variant) = receiver {
+            if let Some(value) = variant.field(name) {
+                return Ok(value.clone());
+            }
+        }
+        match (receiver, name) {
+            (Value::Generator(generator), "isDone") => {
+                Ok((generator.borrow().state == GeneratorState::Done).into())
+            }
+            (Value::Fiber(fiber), "isDone") => {
+                Ok((fiber.borrow().state == FiberState::Done).into())
+            }
+            _ => error::RuntimeError {
+                msg: format!("Undefined property '{}' on {}.", name, receiver),
+                line: self.current_line(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Calls the built-in method `name` on the receiver below the arguments.
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<()> {
+        let receiver = self.peek(arg_count)?.to_value();
+        if let Value::Instance(instance) = &receiver {
+            let (getter, field, method) = {
+                let instance = instance.borrow();
+                (
+                    instance.find(MethodKind::Getter, name),
+                    instance.fields.get(name).cloned(),
+                    instance.find_method(name),
+                )
+            };
+            if let Some(getter) = getter {
+                return self.call_getter_result(getter, receiver, arg_count);
+            }
+            if let Some(field) = field {
+                let slot = self.slot_from_top(arg_count)?;
+                *self.slot_mut(slot)? = field.clone().into_slot();
+                return self.call_value(field, arg_count);
+            }
+            if let Some(method) = method {
+                return self.call_closure(method, arg_count);
+            }
+        }
+        if let Value::Class(class) = &receiver {
+            let method = class.borrow().static_methods.get(name).cloned();
+            if let Some(method) = method {
+                return self.call_closure(method, arg_count);
+            }
+        }
+        if receiver.is_enum() || receiver.is_variant() {
+            let callee = self.get_property(&receiver, name)?;
+            let slot = self.slot_from_top(arg_count)?;
+            *self.slot_mut(slot)? = callee.clone().into_slot();
+            return self.call_value(callee, arg_count);
+        }
+        match (&receiver, name) {
+            (Value::Generator(generator), "next") => {
+                self.check_method_arity(arg_count, 0)?;
+                self.pop()?;
+                self.resume_generator(generator.clone(), OnReturn::Push)
+            }
+            (Value::List(list), "len") => {
+                self.check_method_arity(arg_count, 0)?;
+                let len = list.borrow().len() as i64;
+                self.pop()?;
+                self.push(Value::Int(len))
+            }
+            (Value::List(list), "push") if arg_count == 1 => {
+                let value = self.pop()?;
+                list.borrow_mut().push(value);
+                self.pop()?;
+                self.push(Value::Nil)
+            }
+            (Value::List(list), "pop") => {
+                self.check_method_arity(arg_count, 0)?;
+                let value = list
+                    .borrow_mut()
+                    .pop()
+                    .with_context(|| error::RuntimeError {
+                        msg: "Can't pop from an empty list.",
+                        line: self.current_line(),
+                    })?;
+                self.pop()?;
+                self.push(value)
+            }
+            (Value::Map(map), "len") => {
+                self.check_method_arity(arg_count, 0)?;
+                let len = map.borrow().len() as i64;
+                self.pop()?;
+                self.push(Value::Int(len))
+            }
+            (Value::Map(map), "has") if arg_count == 1 => {
+                let key = self.pop()?;
+                let key = self.map_key(&key)?;
+                let has = map.borrow().contains_key(&key);
+                self.pop()?;
+                self.push(has.into())
+            }
+            (Value::Builtin(Builtin::Fiber), "new") => {
+                let function = match self.peek(0)?.as_closure() {
+                    Ok(closure) if arg_count == 1 => closure.clone(),
+                    _ => {
+                        return error::RuntimeError {
+                            msg: "Fiber.new() expects a function.",
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
+                };
+                if function.function.param_slots() > 1 || function.function.is_generator {
+                    return error::RuntimeError {
+                        msg: "Fiber function must take at most one parameter and can't yield.",
+                        line: self.current_line(),
+                    }
+                    .fail();
+                }
+                self.pop()?;
+                self.pop()?;
+                let fiber = Fiber::new(vec![Value::Closure(function).into_slot()], FiberState::New);
+                self.push(Value::Fiber(Rc::new(RefCell::new(fiber))))?;
+                Ok(())
+            }
+            (Value::Builtin(Builtin::Fiber), "yield") => {
+                self.check_method_arity(arg_count, 1)?;
+                let value = self.pop_optional_arg(arg_count)?;
+                self.yield_fiber(value)
+            }
+            (Value::Fiber(fiber), "call") | (Value::Fiber(fiber), "try") => {
+                self.check_method_arity(arg_count, 1)?;
+                let value = self.pop_optional_arg(arg_count)?;
+                self.call_fiber(fiber.clone(), value, name == "try")
+            }
+            _ => error::RuntimeError {
+                msg: format!("Undefined method '{}' on {}.", name, receiver),
+                line: self.current_line(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Pushes the next element of the iterable in `slot`, whose iteration state
+    /// is in the slot after it, or continues at `exit` when there is none.
+    /// `start` is the ip of the `OpForIter` instruction.
+    fn iterate(&mut self, slot: usize, start: usize, exit: usize) -> Result<()> {
+        let iterable = self.slot(slot)?;
+        if let Ok(list) = iterable.as_list() {
+            let index = self.slot(slot + 1)?.as_i64().unwrap_or(0) as usize;
+            let item = list.borrow().get(index).cloned();
+            match item {
+                Some(item) => {
+                    *self.slot_mut(slot + 1)? = Value::Int(index as i64 + 1).into_slot();
+                    self.push(item)?;
+                }
+                None => self.frame_mut()?.ip = exit,
+            }
+            return Ok(());
+        }
+        if let Ok(map) = iterable.as_map() {
+            // The state is the last key, so the map may change in between.
+            let key = {
+                let map = map.borrow();
+                let mut keys = match self.slot(slot + 1)?.as_str() {
+                    Ok(last) => map.range::<String, _>((Excluded(last), Unbounded)),
+                    Err(_) => map.range::<String, _>(..),
+                };
+                keys.next().map(|(key, _)| key.clone())
+            };
+            match key {
+                Some(key) => {
+                    *self.slot_mut(slot + 1)? = Value::from(key.clone()).into_slot();
+                    self.push(key.into())?;
+                }
+                None => self.frame_mut()?.ip = exit,
+            }
+            return Ok(());
+        }
+        if let Ok(instance) = iterable.as_instance() {
+            let instance = instance.clone();
+            let mut iterator = self.slot(slot + 1)?.clone();
+            if iterator.is_nil() {
+                let iterable = self.slot(slot)?.clone();
+                let iterate = instance.borrow().find_method("iterate");
+                if let Some(iterate) = iterate {
+                    let on_return = OnReturn::Iterate { slot, ip: start };
+                    return self.call_iterator_method(iterable, iterate, on_return);
+                }
+                // An instance with only `next()` is its own iterator.
+                iterator = iterable;
+                *self.slot_mut(slot + 1)? = iterator.clone();
+            }
+            let next = Self::instance_method(&iterator, MethodKind::Method, "next");
+            let next = next.with_context(|| error::RuntimeError {
+                msg: format!("Can't iterate over {}: it has no next() method.", iterator),
+                line: self.current_line(),
+            })?;
+            return self.call_iterator_method(iterator, next, OnReturn::Next(exit));
+        }
+        if let Ok(s) = iterable.as_str() {
+            let position = self.slot(slot + 1)?.as_i64().unwrap_or(0) as usize;
+            match s[position..].chars().next() {
+                Some(c) => {
+                    let next = (position + c.len_utf8()) as i64;
+                    *self.slot_mut(slot + 1)? = Value::Int(next).into_slot();
+                    self.push(c.to_string().into())?;
+                }
+                None => self.frame_mut()?.ip = exit,
+            }
+            return Ok(());
+        }
+        if let Ok(range) = iterable.as_range() {
+            // The state is the next integer, or `true` once past `i64::MAX`.
+            let state = self.slot(slot + 1)?;
+            let next = if state.is_nil() {
+                Some(range.start)
+            } else {
+                state.as_i64()
+            };
+            match next.filter(|next| range.contains(*next)) {
+                Some(next) => {
+                    *self.slot_mut(slot + 1)? = next
+                        .checked_add(1)
+                        .map_or(Value::Bool(true), Value::Int)
+                        .into_slot();
+                    self.push(Value::Int(next))?;
+                }
+                None => self.frame_mut()?.ip = exit,
+            }
+            return Ok(());
+        }
+        if let Ok(generator) = iterable.as_generator() {
+            let generator = generator.clone();
+            return self.resume_generator(generator, OnReturn::ExitLoop(exit));
+        }
+        error::RuntimeError {
+            msg: format!("Can't iterate over {}.", iterable),
+            line: self.current_line(),
+        }
+        .fail()
+    }
+
+    /// Checks that `index` is an integer within `list`.
+    fn list_index(&self, list: &[Value], index: &Value) -> Result<usize> {
+        match *index {
+            Value::Int(i) if i >= 0 && (i as usize) < list.len() => Ok(i as usize),
+            Value::Int(i) => error::RuntimeError {
+                msg: format!(
+                    "List index {} out of bounds for list of length {}.",
+                    i,
+                    list.len()
+                ),
+                line: self.current_line(),
+            }
+            .fail(),
+            _ => error::RuntimeError {
+                msg: format!("List index must be an integer but got {}.", index),
+                line: self.current_line(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Checks that `range` is within a `what` of `len` items, returning its
+    /// start and exclusive end.
+    fn slice_bounds(&self, range: &Range, len: usize, what: &str) -> Result<(usize, usize)> {
+        range
+            .slice_bounds(len)
+            .with_context(|| error::RuntimeError {
+                msg: format!(
+                    "Slice {} out of bounds for {} of length {}.",
+                    range, what, len
+                ),
+                line: self.current_line(),
+            })
+    }
+
+    /// Prints what an instance's `__str()` returned, which must be a string.
+    fn print_str_result(&mut self, result: Value) -> Result<()> {
+        match result {
+            Value::Str(s) => {
+                writeln!(self.out, "{}", s).expect("write output");
+                Ok(())
+            }
+            _ => error::RuntimeError {
+                msg: format!("__str() must return a string but got {}.", result),
+                line: self.current_line(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Checks that `key` is a string, the only kind of map key.
+    fn map_key(&self, key: &Value) -> Result<String> {
+        match key {
+            Value::Str(key) => Ok(key.clone()),
+            _ => error::RuntimeError {
+                msg: format!("Map keys must be strings but got {}.", key),
+                line: self.current_line(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Pushes the elements of the list `value`: `count` of them and, with
+    /// `has_rest`, a new list of the ones after.
+    fn unpack_list(&mut self, value: Value, count: usize, has_rest: bool) -> Result<()> {
+        let list = value
+            .as_list()
+            .ok()
+            .cloned()
+            .with_context(|| error::RuntimeError {
+                msg: format!("Expected a list to unpack but got {}.", value),
+                line: self.current_line(),
+            })?;
+        let items = list.borrow().clone();
+        if items.len() < count || (!has_rest && items.len() > count) {
+            return error::RuntimeError {
+                msg: format!(
+                    "Expected {}{} elements to unpack but got {}.",
+                    if has_rest { "at least " } else { "" },
+                    count,
+                    items.len()
+                ),
+                line: self.current_line(),
+            }
+            .fail();
+        }
+        let mut items = items.into_iter();
+        for item in items.by_ref().take(count) {
+            self.push(item)?;
+        }
+        if has_rest {
+            self.push(Value::new_list(items.collect()))?;
         }
+        Ok(())
     }
 
-    pub fn write(&mut self, byte: u8, line: u32) {
-        self.chunk.write(byte, line)
+    /// Pushes the fields `names` of the instance `value`.
+    fn unpack_fields(&mut self, value: Value, names: Vec<String>) -> Result<()> {
+        for name in names {
+            let field = match &value {
+                Value::Instance(instance) => instance.borrow().fields.get(&name).cloned(),
+                Value::Variant(variant) => variant.field(&name).cloned(),
+                _ => {
+                    return error::RuntimeError {
+                        msg: format!(
+                            "Can only destructure fields of instances and enum variants, not {}.",
+                            value
+                        ),
+                        line: self.current_line(),
+                    }
+                    .fail()
+                }
+            };
+            let field = field.with_context(|| error::RuntimeError {
+                msg: format!("Missing field '{}' to unpack from {}.", name, value),
+                line: self.current_line(),
+            })?;
+            self.push(field)?;
+        }
+        Ok(())
     }
 
-    pub fn write_opcode(&mut self, opcode: OpCode, line: u32) {
-        self.chunk.write(opcode as u8, line)
+    /// Calls `method`, an `iterate()` or `next()` method of `receiver`, for a
+    /// for-in loop. `on_return` says what the loop does with the result.
+    fn call_iterator_method(
+        &mut self,
+        receiver: Slot,
+        method: Rc<Closure>,
+        on_return: OnReturn,
+    ) -> Result<()> {
+        let depth = self.frames.len();
+        self.push_slot(receiver)?;
+        self.call_closure(method, 0)?;
+        if self.frames.len() > depth {
+            self.frame_mut()?.on_return = on_return;
+            return Ok(());
+        }
+        // A generator method returned its generator without running.
+        let result = self.pop_slot()?;
+        self.iterator_result(result, on_return)
     }
 
-    pub fn write_constant(&mut self, value: Value, line: u32) {
-        self.chunk.write_constant(value, line)
+    /// Continues a for-in loop with the result of its iterator method.
+    fn iterator_result(&mut self, result: Slot, on_return: OnReturn) -> Result<()> {
+        match on_return {
+            OnReturn::Iterate { slot, ip } => {
+                if result.is_nil() {
+                    return error::RuntimeError {
+                        msg: "iterate() must return an iterator but got nil.",
+                        line: self.current_line(),
+                    }
+                    .fail();
+                }
+                if result.is_instance() {
+                    // An instance is the iterator whose `next()` the loop calls.
+                    *self.slot_mut(slot + 1)? = result;
+                } else {
+                    // Anything else is iterated over in place of the instance.
+                    *self.slot_mut(slot)? = result;
+                    *self.slot_mut(slot + 1)? = Value::Nil.into_slot();
+                }
+                self.frame_mut()?.ip = ip;
+            }
+            OnReturn::Next(exit) if result.is_nil() => self.frame_mut()?.ip = exit,
+            _ => self.push_slot(result)?,
+        }
+        Ok(())
     }
 
-    pub fn interpret_source(&mut self, source: &str) -> Result<bool> {
-        let mut compilier = Compiler::new(source.as_bytes(), &mut self.chunk);
-        let ret = compilier.compile()?;
-        self.run()?;
-        Ok(ret)
+    /// Looks `name` up in `superclass` and binds it to `receiver`.
+    fn find_super_method(&self, superclass: &Value, name: &str) -> Result<Rc<Closure>> {
+        let method = superclass
+            .as_class()
+            .ok()
+            .and_then(|class| class.borrow().methods.get(name).cloned());
+        method.with_context(|| error::RuntimeError {
+            msg: format!("Undefined property '{}'.", name),
+            line: self.current_line(),
+        })
     }
 
-    fn read_byte(&mut self) -> u8 {
-        assert!(self.chunk.code.len() > self.ip);
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
-        byte
+    /// Looks up a method, getter or setter of `value`, if it is an instance.
+    fn instance_method(value: &Slot, kind: MethodKind, name: &str) -> Option<Rc<Closure>> {
+        value.as_instance().ok()?.borrow().find(kind, name)
     }
 
-    fn read_constant(&mut self) -> Value {
-        let constant = self.read_byte();
-        assert!(self.chunk.constants.len() > constant as usize);
-        self.chunk.constants[constant as usize].clone()
+    /// Calls the left operand's `method` with the right operand as argument.
+    /// Failing that, calls the right operand's `reflected` method, if given,
+    /// with the operands swapped: `__eq` for `==`, `__gt` for `<` and `__lt`
+    /// for `>`.
+    fn call_operator(&mut self, method: &str, symbol: &str, reflected: Option<&str>) -> Result<()> {
+        if let Some(method) = Self::instance_method(self.peek(1)?, MethodKind::Method, method) {
+            return self.call_closure(method, 1);
+        }
+        if let Some(reflected) = reflected {
+            if let Some(method) =
+                Self::instance_method(self.peek(0)?, MethodKind::Method, reflected)
+            {
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+                return self.call_closure(method, 1);
+            }
+        }
+        self.unsupported_operator(symbol)
     }
 
-    fn read_constant_long(&mut self) -> Value {
-        let constant = read_u24(&[0, self.read_byte(), self.read_byte(), self.read_byte()]);
-        assert!(self.chunk.constants.len() > constant as usize);
-        self.chunk.constants[constant as usize].clone()
+    fn unsupported_operator(&self, symbol: &str) -> Result<()> {
+        error::RuntimeError {
+            msg: format!(
+                "Unsupported operands for '{}': {} and {}.",
+                symbol,
+                self.peek(1)?,
+                self.peek(0)?
+            ),
+            line: self.current_line(),
+        }
+        .fail()
     }
 
-    pub fn push(&mut self, value: Value) {
-        self.stack.push(value)
+    /// Whether a binary operator has to look for an operator method.
+    fn has_instance_operand(&self) -> Result<bool> {
+        Ok(self.peek(0)?.is_instance() || self.peek(1)?.is_instance())
     }
 
-    pub fn pop(&mut self) -> Result<Value> {
-        self.stack
-            .pop()
-            .context(error::NoOpCodeError { msg: "pop error" })
+    /// Pops the receiver and its single optional argument, `nil` if missing.
+    fn pop_optional_arg(&mut self, arg_count: usize) -> Result<Value> {
+        let value = if arg_count == 1 {
+            self.pop()?
+        } else {
+            Value::Nil
+        };
+        self.pop()?;
+        Ok(value)
     }
 
-    pub fn peek(&self, index: usize) -> Result<&Value> {
-        self.stack
-            .get(self.stack.len() - index - 1)
-            .context(error::NoOpCodeError {
-                msg: format!("peek {}", index),
-            })
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s == slot));
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at `last` or above.
+    fn close_upvalues(&mut self, last: usize) -> Result<()> {
+        let stack = &self.stack;
+        let mut closed = Ok(());
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) | Upvalue::Parked(..) => return false,
+            };
+            if slot < last {
+                return true;
+            }
+            match stack.get(slot) {
+                Some(value) => *upvalue.borrow_mut() = Upvalue::Closed(value.clone()),
+                None => {
+                    closed = error::NoOpCodeError {
+                        msg: format!("stack slot {}", slot),
+                    }
+                    .fail()
+                }
+            }
+            false
+        });
+        closed
+    }
+
+    /// An operand of the wrong type for an operator, as a runtime error.
+    fn operand_error(&self, err: ValueTypeError) -> Error {
+        Error::RuntimeError {
+            msg: err.msg().to_string(),
+            line: self.current_line(),
+        }
+    }
+
+    /// Integer division and modulo by zero are errors; float division by zero
+    /// follows IEEE 754.
+    fn check_integer_divisor(&self) -> Result<()> {
+        if self.peek(0)?.as_i64() == Some(0) && self.peek(1)?.is_integer() {
+            return error::RuntimeError {
+                msg: "division by zero",
+                line: self.current_line(),
+            }
+            .fail();
+        }
+        Ok(())
     }
 
     fn run(&mut self) -> Result<()> {
+        loop {
+            match self.execute() {
+                Err(err) if self.catch_error(&err) => continue,
+                ret => return ret,
+            }
+        }
+    }
+
+    /// Runs the current frame's code until the script is done or an error is
+    /// raised. The frame's code, ip and first slot live in locals; the ip is
+    /// written back to the frame only by instructions that may switch frames
+    /// and when an error is raised.
+    fn execute(&mut self) -> Result<()> {
+        let (mut code, mut ip, mut slots) = self.frame_code()?;
+        loop {
+            let mut switched = false;
+            match self.step(&code, &mut ip, slots, &mut switched) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(mut err) => {
+                    // Errors raised in the instruction's own frame were built
+                    // with the ip of the frame's last call.
+                    if let (false, Some(frame)) = (switched, self.frames.last_mut()) {
+                        frame.ip = ip;
+                        err.set_line(self.current_line());
+                    }
+                    return Err(err);
+                }
+            }
+            if switched {
+                (code, ip, slots) = self.frame_code()?;
+            }
+        }
+    }
+
+    /// Runs the instruction at `ip` in `code`, whose frame's first slot is
+    /// `slots`. Sets `switched` before anything that may switch frames, after
+    /// writing `ip` back to the frame. Returns false once the script is done.
+    #[inline(always)]
+    fn step(
+        &mut self,
+        code: &Code,
+        ip: &mut usize,
+        slots: usize,
+        switched: &mut bool,
+    ) -> Result<bool> {
+        // Hands the ip to the frame, which holds it from here on.
+        macro_rules! sync {
+            () => {
+                self.frame_mut()?.ip = *ip;
+                *switched = true;
+            };
+        }
+
         macro_rules! binary_op {
             ($op:expr, $ty:tt, $err_msg:expr) => {
-                let left = self.pop()?.$ty().context(error::TypeError {
+                let left = self.pop()?.$ty().with_context(|| error::TypeError {
                     msg: $err_msg,
-                    line: self.chunk.lines.get(self.ip) as usize,
+                    line: self.current_line(),
                 })?;
-                let right = self.pop()?.$ty().context(error::TypeError {
+                let right = self.pop()?.$ty().with_context(|| error::TypeError {
                     msg: $err_msg,
-                    line: self.chunk.lines.get(self.ip) as usize,
-                })?;;
-                self.push($op(right, left).into());
+                    line: self.current_line(),
+                })?;
+                self.push($op(right, left).into())?;
             };
             ($op:expr) => {
+                let left = self.pop_slot()?;
+                let right = self.pop_slot()?;
+                self.push($op(right, left).into())?;
+            };
+        }
+
+        // Integer operands stay integers, promoting to BigInt when the i64
+        // operation overflows; anything involving a Number is done in f64.
+        macro_rules! arithmetic_op {
+            ($int_op:expr, $big_op:expr, $float_op:expr) => {
+                let right = self.pop()?;
                 let left = self.pop()?;
+                let value = Value::arithmetic(&left, &right, $int_op, $big_op, $float_op)
+                    .map_err(|err| self.operand_error(err))?;
+                self.push(value)?;
+            };
+        }
+
+        macro_rules! compare_op {
+            ($ordering:pat) => {
                 let right = self.pop()?;
-                self.push($op(right, left).into());
+                let left = self.pop()?;
+                let ordering = left
+                    .compare_numbers(&right)
+                    .map_err(|err| self.operand_error(err))?;
+                self.push(matches!(ordering, Some($ordering)).into())?;
             };
         }
 
-        loop {
-            if cfg!(feature = "debug-trace-execution") {
-                print!("      ");
-                for slot in &self.stack {
-                    print!("[ ");
-                    print_value(slot);
-                    print!(" ]");
-                }
-                println!();
-                disassemble_instruction(&self.chunk, self.ip);
-            }
-
-            let instruction: OpCode = self.read_byte().try_into().expect("read byte");
-            match instruction {
-                OpCode::OpReturn => {
-                    print_value(&self.pop()?);
-                    println!();
-                    return Ok(());
-                }
-                OpCode::OpConstant => {
-                    let constant = self.read_constant();
-                    self.push(constant);
-                }
-                OpCode::OpConstantLong => {
-                    let constant = self.read_constant_long();
-                    self.push(constant);
-                }
-                OpCode::OpNegate => {
-                    let constant = self.pop()?;
-                    self.push(
-                        (-constant.to_number().context(error::TypeError {
-                            msg: "no number value",
-                            line: self.chunk.lines.get(self.ip) as usize,
-                        })?)
-                        .into(),
-                    );
+        // Two `Int`s on top of the stack are the common case, replaced by the
+        // result in place. Anything else, overflow included, goes on to the
+        // general path.
+        macro_rules! int_op {
+            ($op:expr) => {
+                if let [.., left, right] = &self.stack[..] {
+                    if let (Some(left), Some(right)) = (left.as_i64(), right.as_i64()) {
+                        if let Some(value) = $op(left, right) {
+                            let len = self.stack.len();
+                            self.stack[len - 2] = value.into_slot();
+                            self.stack.truncate(len - 1);
+                            return Ok(true);
+                        }
+                    }
+                }
+            };
+        }
+
+        let chunk = code.chunk();
+
+        #[cfg(feature = "debug-trace-execution")]
+        {
+            print!("      ");
+            for slot in &self.stack {
+                print!("[ ");
+                print_value(slot);
+                print!(" ]");
+            }
+            println!();
+            disassemble_instruction(chunk, *ip);
+        }
+
+        let instruction = next_op(&chunk.code, ip);
+        match instruction {
+            OpCode::OpReturn => {
+                sync!();
+                let result = self.pop_slot()?;
+                let frame = self.pop_frame()?;
+                self.close_upvalues(frame.slots)?;
+                if self.frames.is_empty() {
+                    if self.finish_fiber().is_none() {
+                        self.stack.truncate(frame.slots);
+                        return Ok(false);
+                    }
+                    self.push_slot(result)?;
+                    return Ok(true);
+                }
+                self.stack.truncate(frame.slots);
+                if let Some(generator) = &frame.generator {
+                    generator.borrow_mut().state = GeneratorState::Done;
+                }
+                match frame.on_return {
+                    OnReturn::ExitLoop(exit) => self.frame_mut()?.ip = exit,
+                    OnReturn::Call(arg_count) => {
+                        let slot = self.slot_from_top(arg_count)?;
+                        *self.slot_mut(slot)? = result.clone();
+                        self.call_value(result.into_value(), arg_count)?;
+                    }
+                    OnReturn::Iterate { .. } | OnReturn::Next(_) => {
+                        self.iterator_result(result, frame.on_return)?
+                    }
+                    OnReturn::Print => self.print_str_result(result.into_value())?,
+                    OnReturn::Push if frame.generator.is_some() => self.push(Value::Nil)?,
+                    OnReturn::Push => self.push_slot(result)?,
+                }
+            }
+            OpCode::OpYield => {
+                sync!();
+                let value = self.pop_slot()?;
+                let frame = self.pop_frame()?;
+                self.suspend_generator(frame)?;
+                self.push_slot(value)?;
+            }
+            OpCode::OpGetProperty | OpCode::OpGetPropertyLong => {
+                let index = next_index(&chunk.code, ip, instruction == OpCode::OpGetPropertyLong);
+                let name = self.constant_str(chunk, index)?;
+                let getter = Self::instance_method(self.peek(0)?, MethodKind::Getter, name);
+                match getter {
+                    Some(getter) => {
+                        sync!();
+                        self.call_closure(getter, 0)?;
+                    }
+                    None => {
+                        let receiver = self.pop()?;
+                        let value = self.get_property(&receiver, name)?;
+                        self.push(value)?;
+                    }
+                }
+            }
+            OpCode::OpInvoke | OpCode::OpInvokeLong => {
+                let index = next_index(&chunk.code, ip, instruction == OpCode::OpInvokeLong);
+                let name = self.constant_str(chunk, index)?;
+                let arg_count = next_byte(&chunk.code, ip) as usize;
+                sync!();
+                self.invoke(name, arg_count)?;
+            }
+            OpCode::OpPrint => {
+                if let Some(method) =
+                    Self::instance_method(self.peek(0)?, MethodKind::Method, "__str")
+                {
+                    sync!();
+                    let depth = self.frames.len();
+                    self.call_closure(method, 0)?;
+                    if self.frames.len() > depth {
+                        self.frame_mut()?.on_return = OnReturn::Print;
+                    } else {
+                        // A generator `__str` returned its generator.
+                        let result = self.pop()?;
+                        self.print_str_result(result)?;
+                    }
+                    return Ok(true);
+                }
+                let value = self.pop()?;
+                writeln!(self.out, "{}", value).expect("write output");
+            }
+            OpCode::OpPop => {
+                self.pop_slot()?;
+            }
+            OpCode::OpDefineGlobal | OpCode::OpDefineGlobalLong => {
+                let long = instruction == OpCode::OpDefineGlobalLong;
+                let index = next_index(&chunk.code, ip, long);
+                let name = self.constant_str(chunk, index)?.to_string();
+                let value = self.pop_slot()?;
+                self.globals.insert(name, value);
+            }
+            OpCode::OpGetGlobal | OpCode::OpGetGlobalLong => {
+                let long = instruction == OpCode::OpGetGlobalLong;
+                let index = next_index(&chunk.code, ip, long);
+                let name = self.constant_str(chunk, index)?;
+                let value = self
+                    .globals
+                    .get(name)
+                    .with_context(|| error::RuntimeError {
+                        msg: format!("Undefined variable '{}'.", name),
+                        line: self.current_line(),
+                    })?
+                    .clone();
+                self.push_slot(value)?;
+            }
+            OpCode::OpSetGlobal | OpCode::OpSetGlobalLong => {
+                let long = instruction == OpCode::OpSetGlobalLong;
+                let index = next_index(&chunk.code, ip, long);
+                let name = self.constant_str(chunk, index)?;
+                let value = self.peek(0)?.clone();
+                match self.globals.get_mut(name) {
+                    Some(global) => *global = value,
+                    None => {
+                        return error::RuntimeError {
+                            msg: format!("Undefined variable '{}'.", name),
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
+                }
+            }
+            OpCode::OpGetLocal => {
+                let slot = slots + next_byte(&chunk.code, ip) as usize;
+                let value = self.slot(slot)?.clone();
+                self.push_slot(value)?;
+            }
+            OpCode::OpSetLocal => {
+                let slot = slots + next_byte(&chunk.code, ip) as usize;
+                *self.slot_mut(slot)? = self.peek(0)?.clone();
+            }
+            OpCode::OpGetUpvalue => {
+                let index = next_byte(&chunk.code, ip) as usize;
+                let upvalue = code.upvalue(index)?;
+                let value = match &*upvalue.borrow() {
+                    Upvalue::Open(slot) => self.slot(*slot)?.clone(),
+                    Upvalue::Closed(value) => value.clone(),
+                    Upvalue::Parked(stack, index) => parked_slot(&stack.borrow(), *index)?.clone(),
+                };
+                self.push_slot(value)?;
+            }
+            OpCode::OpSetUpvalue => {
+                let index = next_byte(&chunk.code, ip) as usize;
+                let upvalue = code.upvalue(index)?;
+                let value = self.peek(0)?.clone();
+                let mut upvalue = upvalue.borrow_mut();
+                match &mut *upvalue {
+                    Upvalue::Open(slot) => *self.slot_mut(*slot)? = value,
+                    Upvalue::Closed(closed) => *closed = value,
+                    Upvalue::Parked(stack, index) => {
+                        *parked_slot_mut(&mut stack.borrow_mut(), *index)? = value
+                    }
                 }
-                OpCode::OpAdd => {
-                    if self.peek(0)?.is_str() && self.peek(1)?.is_str() {
-                        binary_op!(|l, r| format!("{}{}", l, r), into_str, "not a str");
+            }
+            OpCode::OpCloseUpvalue => {
+                let slot = self.slot_from_top(0)?;
+                self.close_upvalues(slot)?;
+                self.pop()?;
+            }
+            OpCode::OpClosure | OpCode::OpClosureLong => {
+                let long = instruction == OpCode::OpClosureLong;
+                let index = next_index(&chunk.code, ip, long);
+                let function =
+                    constant(chunk, index)
+                        .to_function()
+                        .with_context(|| error::TypeError {
+                            msg: "closure constant",
+                            line: self.current_line(),
+                        })?;
+                let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                for _ in 0..function.upvalue_count {
+                    let is_local = next_byte(&chunk.code, ip) == 1;
+                    let index = next_byte(&chunk.code, ip) as usize;
+                    if is_local {
+                        upvalues.push(self.capture_upvalue(slots + index));
                     } else {
-                        binary_op!(|l, r| l + r, into_number, "not a str");
+                        upvalues.push(code.upvalue(index)?.clone());
+                    }
+                }
+                self.push(Value::Closure(Rc::new(Closure { function, upvalues })))?;
+            }
+            OpCode::OpCall => {
+                let arg_count = next_byte(&chunk.code, ip) as usize;
+                sync!();
+                let callee = self.peek(arg_count)?.to_value();
+                self.call_value(callee, arg_count)?;
+            }
+            OpCode::OpTailCall => {
+                let arg_count = next_byte(&chunk.code, ip) as usize;
+                sync!();
+                let callee = self.peek(arg_count)?.to_value();
+                self.tail_call(callee, arg_count)?;
+            }
+            OpCode::OpCallNamed => {
+                let positional = next_byte(&chunk.code, ip) as usize;
+                let named = next_byte(&chunk.code, ip) as usize;
+                let mut names = Vec::with_capacity(named);
+                for _ in 0..named {
+                    let index = next_index(&chunk.code, ip, true);
+                    names.push(self.constant_str(chunk, index)?.to_string());
+                }
+                sync!();
+                self.call_named(positional, names)?;
+            }
+            OpCode::OpLoop => {
+                let offset = next_short(&chunk.code, ip);
+                *ip -= offset;
+            }
+            OpCode::OpForIter => {
+                let start = *ip - 1;
+                let slot = slots + next_byte(&chunk.code, ip) as usize;
+                let jump = next_short(&chunk.code, ip);
+                sync!();
+                self.iterate(slot, start, *ip + jump)?;
+            }
+            OpCode::OpJump => {
+                let jump = next_short(&chunk.code, ip);
+                *ip += jump;
+            }
+            OpCode::OpJumpIfFalse => {
+                let jump = next_short(&chunk.code, ip);
+                if self.peek(0)?.is_falsey() {
+                    *ip += jump;
+                }
+            }
+            OpCode::OpJumpIfNil | OpCode::OpJumpIfNotNil => {
+                let jump = next_short(&chunk.code, ip);
+                if self.peek(0)?.is_nil() == (instruction == OpCode::OpJumpIfNil) {
+                    *ip += jump;
+                }
+            }
+            OpCode::OpAssertFail | OpCode::OpAssertFailLong => {
+                let long = instruction == OpCode::OpAssertFailLong;
+                let index = next_index(&chunk.code, ip, long);
+                let source = self.constant_str(chunk, index)?.to_string();
+                let msg = match self.pop()? {
+                    Value::Nil => source,
+                    message => format!("{}: {}", source, message),
+                };
+                return error::AssertionError {
+                    line: self.current_line(),
+                    msg,
+                }
+                .fail();
+            }
+            OpCode::OpMatch => {
+                let slot = slots + next_byte(&chunk.code, ip) as usize;
+                let index = next_index(&chunk.code, ip, true);
+                let pattern =
+                    constant(chunk, index)
+                        .as_pattern()
+                        .with_context(|| error::TypeError {
+                            msg: "pattern",
+                            line: self.current_line(),
+                        })?;
+                let jump = next_short(&chunk.code, ip);
+                let mut bindings = Vec::new();
+                let matched = self
+                    .slot(slot)?
+                    .with(|value| pattern.matches(value, &mut bindings));
+                if matched {
+                    self.reserve(bindings.len())?;
+                    self.stack
+                        .extend(bindings.into_iter().map(Value::into_slot));
+                } else {
+                    *ip += jump;
+                }
+            }
+            OpCode::OpNoMatch => {
+                let slot = slots + next_byte(&chunk.code, ip) as usize;
+                return error::RuntimeError {
+                    msg: format!("No match arm matches {}.", self.slot(slot)?),
+                    line: self.current_line(),
+                }
+                .fail();
+            }
+            OpCode::OpSkipIfArg => {
+                let param = (next_byte(&chunk.code, ip) as usize)
+                    .checked_sub(1)
+                    .with_context(|| error::NoOpCodeError { msg: "parameter 0" })?;
+                let jump = next_short(&chunk.code, ip);
+                if self
+                    .frame()?
+                    .args_passed
+                    .get(param)
+                    .copied()
+                    .unwrap_or(true)
+                {
+                    *ip += jump;
+                }
+            }
+            OpCode::OpConstant | OpCode::OpConstantLong => {
+                let long = instruction == OpCode::OpConstantLong;
+                let index = next_index(&chunk.code, ip, long);
+                self.push_slot(constant(chunk, index).clone())?;
+            }
+            OpCode::OpClass | OpCode::OpClassLong | OpCode::OpTrait | OpCode::OpTraitLong => {
+                let long = instruction == OpCode::OpClassLong || instruction == OpCode::OpTraitLong;
+                let index = next_index(&chunk.code, ip, long);
+                let name = self.constant_str(chunk, index)?.to_string();
+                let is_trait = instruction == OpCode::OpTrait || instruction == OpCode::OpTraitLong;
+                self.push(Value::Class(Rc::new(RefCell::new(Class::new(
+                    name, is_trait,
+                )))))?;
+            }
+            OpCode::OpMethod | OpCode::OpMethodLong => {
+                let long = instruction == OpCode::OpMethodLong;
+                let index = next_index(&chunk.code, ip, long);
+                let name = self.constant_str(chunk, index)?.to_string();
+                let byte = next_byte(&chunk.code, ip);
+                let kind =
+                    MethodKind::try_from(byte)
+                        .ok()
+                        .with_context(|| error::NoOpCodeError {
+                            msg: format!("unknown method kind {}", byte),
+                        })?;
+                let method = self
+                    .pop()?
+                    .into_closure()
+                    .with_context(|| error::TypeError {
+                        msg: "method",
+                        line: self.current_line(),
+                    })?;
+                let class = self.peek(0)?.to_class().with_context(|| error::TypeError {
+                    msg: "method class",
+                    line: self.current_line(),
+                })?;
+                let mut class = class.borrow_mut();
+                class
+                    .conflicts
+                    .retain(|(other, conflict, _)| *other != kind || *conflict != name);
+                class.table_mut(kind).insert(name, method);
+            }
+            OpCode::OpInherit => {
+                let superclass = match self.peek(1)?.as_class() {
+                    Ok(class) if !class.borrow().is_trait => class.clone(),
+                    _ => {
+                        return error::RuntimeError {
+                            msg: "Superclass must be a class.",
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
+                };
+                let subclass = self.pop()?.into_class().with_context(|| error::TypeError {
+                    msg: "subclass",
+                    line: self.current_line(),
+                })?;
+                let superclass = superclass.borrow();
+                let mut subclass = subclass.borrow_mut();
+                for kind in &MethodKind::ALL {
+                    let methods = superclass.table(*kind).clone();
+                    subclass.table_mut(*kind).extend(methods);
+                }
+            }
+            OpCode::OpUseTraits => {
+                let count = next_byte(&chunk.code, ip) as usize;
+                self.use_traits(count)?;
+            }
+            OpCode::OpEndClass => {
+                let class = self.pop()?.into_class().with_context(|| error::TypeError {
+                    msg: "class",
+                    line: self.current_line(),
+                })?;
+                let class = class.borrow();
+                if let Some((_, name, traits)) = class.conflicts.first() {
+                    return error::RuntimeError {
+                        msg: format!(
+                            "Method '{}' is provided by both {}; class {} must override it.",
+                            name, traits, class.name
+                        ),
+                        line: self.current_line(),
+                    }
+                    .fail();
+                }
+            }
+            OpCode::OpSetProperty | OpCode::OpSetPropertyLong => {
+                let index = next_index(&chunk.code, ip, instruction == OpCode::OpSetPropertyLong);
+                let name = self.constant_str(chunk, index)?;
+                let setter = Self::instance_method(self.peek(1)?, MethodKind::Setter, name);
+                if let Some(setter) = setter {
+                    sync!();
+                    self.call_closure(setter, 1)?;
+                    return Ok(true);
+                }
+                if Self::instance_method(self.peek(1)?, MethodKind::Getter, name).is_some() {
+                    return error::RuntimeError {
+                        msg: format!("Can't assign to getter-only property '{}'.", name),
+                        line: self.current_line(),
+                    }
+                    .fail();
+                }
+                let value = self.pop()?;
+                let instance = match self.pop()? {
+                    Value::Instance(instance) => instance,
+                    _ => {
+                        return error::RuntimeError {
+                            msg: "Only instances have fields.",
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
+                };
+                {
+                    // Only a new field needs its own copy of the name.
+                    let fields = &mut instance.borrow_mut().fields;
+                    match fields.get_mut(name) {
+                        Some(field) => *field = value.clone(),
+                        None => {
+                            fields.insert(name.to_string(), value.clone());
+                        }
+                    }
+                }
+                self.push(value)?;
+            }
+            OpCode::OpGetSuper | OpCode::OpGetSuperLong => {
+                let index = next_index(&chunk.code, ip, instruction == OpCode::OpGetSuperLong);
+                let name = self.constant_str(chunk, index)?;
+                let superclass = self.pop()?;
+                let getter = superclass
+                    .as_class()
+                    .ok()
+                    .and_then(|class| class.borrow().getters.get(name).cloned());
+                if let Some(getter) = getter {
+                    sync!();
+                    self.call_closure(getter, 0)?;
+                    return Ok(true);
+                }
+                let method = self.find_super_method(&superclass, name)?;
+                let receiver = self.pop()?;
+                self.push(Value::BoundMethod(Rc::new(BoundMethod {
+                    receiver,
+                    method,
+                })))?;
+            }
+            OpCode::OpSuperInvoke | OpCode::OpSuperInvokeLong => {
+                let index = next_index(&chunk.code, ip, instruction == OpCode::OpSuperInvokeLong);
+                let name = self.constant_str(chunk, index)?;
+                let arg_count = next_byte(&chunk.code, ip) as usize;
+                let superclass = self.pop()?;
+                let method = self.find_super_method(&superclass, name)?;
+                sync!();
+                self.call_closure(method, arg_count)?;
+            }
+            OpCode::OpRange | OpCode::OpRangeInclusive => {
+                let end = self.pop()?;
+                let start = self.pop()?;
+                let (start, end) = match (start, end) {
+                    (Value::Int(start), Value::Int(end)) => (start, end),
+                    (start, end) => {
+                        return error::RuntimeError {
+                            msg: format!(
+                                "Range bounds must be integers but got {} and {}.",
+                                start, end
+                            ),
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
+                };
+                self.push(Value::Range(Range {
+                    start,
+                    end,
+                    inclusive: instruction == OpCode::OpRangeInclusive,
+                }))?;
+            }
+            OpCode::OpBuildList => {
+                let count = next_byte(&chunk.code, ip) as usize;
+                let first = self.first_of_top(count)?;
+                let items = self.take_values(first);
+                self.push(Value::new_list(items))?;
+            }
+            OpCode::OpBuildMap => {
+                let count = next_byte(&chunk.code, ip) as usize;
+                let first = self.first_of_top(2 * count)?;
+                let mut map = BTreeMap::new();
+                for entry in self.take_values(first).chunks(2) {
+                    let key = self.map_key(&entry[0])?;
+                    map.insert(key, entry[1].clone());
+                }
+                self.push(Value::Map(Rc::new(RefCell::new(map))))?;
+            }
+            OpCode::OpIndex if self.peek(1)?.is_map() => {
+                let key = self.pop()?;
+                let map = self.pop()?.into_map().expect("map");
+                let key = self.map_key(&key)?;
+                let value = map.borrow().get(&key).cloned().unwrap_or(Value::Nil);
+                self.push(value)?;
+            }
+            OpCode::OpIndex if self.peek(1)?.is_list() && self.peek(0)?.is_range() => {
+                let range = self.pop()?.into_range().expect("range");
+                let list = self.pop()?.into_list().expect("list");
+                let (start, end) = self.slice_bounds(&range, list.borrow().len(), "list")?;
+                let slice = list.borrow()[start..end].to_vec();
+                self.push(Value::new_list(slice))?;
+            }
+            OpCode::OpIndex if self.peek(1)?.is_list() => {
+                let index = self.pop()?;
+                let list = self.pop()?.into_list().expect("list");
+                let index = self.list_index(&list.borrow(), &index)?;
+                let value = list.borrow()[index].clone();
+                self.push(value)?;
+            }
+            OpCode::OpSetIndex => {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                match self.pop()? {
+                    Value::List(list) => {
+                        let index = self.list_index(&list.borrow(), &index)?;
+                        list.borrow_mut()[index] = value.clone();
+                    }
+                    Value::Map(map) => {
+                        let key = self.map_key(&index)?;
+                        map.borrow_mut().insert(key, value.clone());
                     }
+                    _ => {
+                        return error::RuntimeError {
+                            msg: "Only list and map elements can be assigned to.",
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
+                }
+                self.push(value)?;
+            }
+            OpCode::OpUnpackList => {
+                let count = next_byte(&chunk.code, ip) as usize;
+                let has_rest = next_byte(&chunk.code, ip) == 1;
+                let value = self.pop()?;
+                self.unpack_list(value, count, has_rest)?;
+            }
+            OpCode::OpUnpackFields => {
+                let count = next_byte(&chunk.code, ip) as usize;
+                let mut names = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let index = next_index(&chunk.code, ip, true);
+                    names.push(self.constant_str(chunk, index)?.to_string());
                 }
-                OpCode::OpSubtract => {
-                    binary_op!(|l, r| l - r, into_number, "not a number");
+                let value = self.pop()?;
+                self.unpack_fields(value, names)?;
+            }
+            OpCode::OpIndex if self.peek(1)?.is_str() && self.peek(0)?.is_range() => {
+                let range = self.pop()?.into_range().expect("range");
+                let s = self.pop()?.into_str().expect("str");
+                let (start, end) = self.slice_bounds(&range, s.chars().count(), "string")?;
+                self.push(
+                    s.chars()
+                        .skip(start)
+                        .take(end - start)
+                        .collect::<String>()
+                        .into(),
+                )?;
+            }
+            OpCode::OpIndex => {
+                match Self::instance_method(self.peek(1)?, MethodKind::Method, "__index") {
+                    Some(method) => {
+                        sync!();
+                        self.call_closure(method, 1)?;
+                    }
+                    None => {
+                        return error::RuntimeError {
+                            msg: format!("Can't index {}.", self.peek(1)?),
+                            line: self.current_line(),
+                        }
+                        .fail()
+                    }
                 }
-                OpCode::OpMultiply => {
-                    binary_op!(|l, r| l * r, into_number, "not a number");
+            }
+            OpCode::OpNegate => {
+                let value = self
+                    .pop()?
+                    .negate()
+                    .map_err(|err| self.operand_error(err))?;
+                self.push(value)?;
+            }
+            OpCode::OpAdd => {
+                int_op!(|l: i64, r| l.checked_add(r).map(Value::Int));
+                if self.has_instance_operand()? {
+                    sync!();
+                    self.call_operator("__add", "+", None)?;
+                } else if self.peek(0)?.is_str() && self.peek(1)?.is_str() {
+                    binary_op!(|l, r| format!("{}{}", l, r), into_str, "not a str");
+                } else if self.peek(0)?.is_str() || self.peek(1)?.is_str() {
+                    return error::RuntimeError {
+                        msg: "Operands must be two numbers or two strings.",
+                        line: self.current_line(),
+                    }
+                    .fail();
+                } else {
+                    arithmetic_op!(i64::checked_add, |l, r| l + r, |l, r| l + r);
                 }
-                OpCode::OpDivide => {
-                    binary_op!(|l, r| l / r, into_number, "not a number");
+            }
+            OpCode::OpSubtract => {
+                int_op!(|l: i64, r| l.checked_sub(r).map(Value::Int));
+                if self.has_instance_operand()? {
+                    sync!();
+                    self.call_operator("__sub", "-", None)?;
+                    return Ok(true);
                 }
-                OpCode::OpNil => {
-                    self.push(Value::Nil);
+                arithmetic_op!(i64::checked_sub, |l, r| l - r, |l, r| l - r);
+            }
+            OpCode::OpMultiply => {
+                int_op!(|l: i64, r| l.checked_mul(r).map(Value::Int));
+                if self.has_instance_operand()? {
+                    sync!();
+                    self.call_operator("__mul", "*", None)?;
+                    return Ok(true);
                 }
-                OpCode::OpFalse => {
-                    self.push(false.into());
+                arithmetic_op!(i64::checked_mul, |l, r| l * r, |l, r| l * r);
+            }
+            OpCode::OpDivide => {
+                int_op!(|l: i64, r| match l.checked_rem(r) {
+                    Some(0) => l.checked_div(r).map(Value::Int),
+                    _ => None,
+                });
+                if self.has_instance_operand()? {
+                    sync!();
+                    self.call_operator("__div", "/", None)?;
+                    return Ok(true);
                 }
-                OpCode::OpTrue => self.push(true.into()),
-                OpCode::OpNot => {
-                    let v = self.pop()?.is_falsey().into();
-                    self.push(v)
+                self.check_integer_divisor()?;
+                let right = self.pop()?;
+                let left = self.pop()?;
+                let value = Value::divide(&left, &right).map_err(|err| self.operand_error(err))?;
+                self.push(value)?;
+            }
+            OpCode::OpModulo => {
+                int_op!(|l: i64, r| l.checked_rem(r).map(Value::Int));
+                if self.has_instance_operand()? {
+                    sync!();
+                    self.call_operator("__mod", "%", None)?;
+                    return Ok(true);
                 }
-                OpCode::OpEqual => {
+                self.check_integer_divisor()?;
+                arithmetic_op!(i64::checked_rem, |l, r| l % r, |l, r| l % r);
+            }
+            OpCode::OpNil => {
+                self.push(Value::Nil)?;
+            }
+            OpCode::OpFalse => {
+                self.push(false.into())?;
+            }
+            OpCode::OpTrue => self.push(true.into())?,
+            OpCode::OpNot => {
+                let v = self.pop_slot()?.is_falsey().into();
+                self.push(v)?
+            }
+            OpCode::OpEqual => {
+                let overloaded = Self::instance_method(self.peek(1)?, MethodKind::Method, "__eq")
+                    .is_some()
+                    || Self::instance_method(self.peek(0)?, MethodKind::Method, "__eq").is_some();
+                if overloaded {
+                    sync!();
+                    self.call_operator("__eq", "==", Some("__eq"))?;
+                } else {
                     binary_op!(|l, r| l == r);
                 }
-                OpCode::OpGreater => {
-                    binary_op!(|l, r| l > r, into_number, "not a number");
+            }
+            OpCode::OpGreater => {
+                int_op!(|l: i64, r| Some(Value::Bool(l > r)));
+                if self.has_instance_operand()? {
+                    sync!();
+                    // `a > b` is `b < a`.
+                    self.call_operator("__gt", ">", Some("__lt"))?;
+                } else {
+                    compare_op!(Ordering::Greater);
+                }
+            }
+            OpCode::OpLess => {
+                int_op!(|l: i64, r| Some(Value::Bool(l < r)));
+                if self.has_instance_operand()? {
+                    sync!();
+                    self.call_operator("__lt", "<", Some("__gt"))?;
+                } else {
+                    compare_op!(Ordering::Less);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Collects what a VM prints.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A VM whose output is collected in the returned buffer.
+    fn vm(options: CompileOptions) -> (VM, Output) {
+        let output = Output::default();
+        let mut vm = VM::with_options(options);
+        vm.out = Box::new(output.clone());
+        (vm, output)
+    }
+
+    fn take_output(output: &Output) -> String {
+        let bytes = std::mem::take(&mut *output.0.borrow_mut());
+        String::from_utf8(bytes).expect("utf-8 output")
+    }
+
+    /// What a test script expects, from its comments: `// expect: line` for
+    /// each line printed, `// expect error: message` for the error it stops
+    /// with, `// trace: line` for each line of its stack trace and
+    /// `// stack-max: n` to run with a smaller stack.
+    #[derive(Debug, Default)]
+    struct Expectations {
+        output: String,
+        error: Option<String>,
+        /// Only checked when the script gives one.
+        trace: Vec<String>,
+        stack_max: Option<usize>,
+    }
+
+    impl Expectations {
+        fn parse(source: &str) -> Self {
+            let mut expectations = Expectations::default();
+            for line in source.lines() {
+                let comment = match line.find("// ") {
+                    Some(start) => &line[start + 3..],
+                    None => continue,
+                };
+                if let Some(text) = comment.strip_prefix("expect: ") {
+                    expectations.output.push_str(text);
+                    expectations.output.push('\n');
+                } else if let Some(text) = comment.strip_prefix("expect error: ") {
+                    expectations.error = Some(text.to_string());
+                } else if let Some(text) = comment.strip_prefix("trace: ") {
+                    expectations.trace.push(text.to_string());
+                } else if let Some(n) = comment.strip_prefix("stack-max: ") {
+                    expectations.stack_max = Some(n.parse().expect("stack-max"));
+                }
+            }
+            expectations
+        }
+    }
+
+    fn lox_files(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).expect("read test dir") {
+            let path = entry.expect("dir entry").path();
+            if path.is_dir() {
+                lox_files(&path, files);
+            } else if path.extension().is_some_and(|ext| ext == "lox") {
+                files.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_declaration_is_forgotten() {
+        let (mut vm, output) = vm(CompileOptions::default());
+        match vm.interpret_source("const x = 1 +;") {
+            Err(Error::CompileError { line, .. }) => assert_eq!(line, 1),
+            other => panic!("unexpected {:?}", other),
+        }
+        vm.interpret_source("var x = 2; print x;").unwrap();
+        assert_eq!(take_output(&output), "2\n");
+    }
+
+    #[test]
+    fn test_asserts_compiled_out() {
+        let (mut vm, output) = vm(CompileOptions {
+            asserts: false,
+            ..CompileOptions::default()
+        });
+        let source = "var n = 0; fun bump() { n = n + 1; return false; } assert bump(); print n;";
+        vm.interpret_source(source).unwrap();
+        assert_eq!(take_output(&output), "0\n");
+    }
+
+    #[test]
+    fn test_stack_access_without_frames_fails() {
+        let (mut vm, _) = vm(CompileOptions::default());
+        assert!(vm.pop().is_err());
+        assert!(vm.peek(0).is_err());
+        assert!(vm.first_of_top(1).is_err());
+        vm.set_stack_max(1);
+        vm.push(Value::Nil).unwrap();
+        assert!(vm.push(Value::Nil).is_err());
+        assert!(vm.frame().is_err());
+    }
+
+    /// The opcodes of the chunk `source` compiles to, after running it.
+    fn opcodes(source: &str, fold_constants: bool) -> Vec<OpCode> {
+        let (mut vm, _) = vm(CompileOptions {
+            fold_constants,
+            ..CompileOptions::default()
+        });
+        vm.interpret_source(source).ok();
+        let mut opcodes = Vec::new();
+        let mut offset = 0;
+        while offset < vm.chunk.code.len() {
+            opcodes.push(OpCode::decode(vm.chunk.code[offset]).expect("opcode"));
+            offset = crate::debug::disassemble_instruction(&vm.chunk, offset);
+        }
+        opcodes
+    }
+
+    /// The size of the constant table `source` compiles to, with folding on.
+    fn constants(source: &str) -> usize {
+        let (mut vm, _) = vm(CompileOptions::default());
+        vm.interpret_source(source).ok();
+        vm.chunk.constants.len()
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let folded = vec![
+            OpCode::OpConstant,
+            OpCode::OpPrint,
+            OpCode::OpNil,
+            OpCode::OpReturn,
+        ];
+        let foldable = [
+            "print 1 + 2 * 3;",
+            "print -(4);",
+            "print !true;",
+            "print \"a\" + \"b\";",
+            "print 1 / 0.0;",
+        ];
+        for source in &foldable {
+            assert_eq!(opcodes(source, true), folded, "{}", source);
+            assert_eq!(constants(source), 1, "{}", source);
+            assert_ne!(opcodes(source, false), folded, "{}", source);
+        }
+        // What the VM rejects is left to fail at runtime.
+        for source in &["print 1 / 0;", "print -\"a\";", "print 1 < \"a\";"] {
+            assert_eq!(opcodes(source, true), opcodes(source, false), "{}", source);
+        }
+    }
+
+    /// Runs every script under `tests/lox` and checks it against its
+    /// expectations, once with constant folding and once without, which must
+    /// not change what a script does.
+    #[test]
+    fn test_lox_files() {
+        let mut files = Vec::new();
+        lox_files(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"),
+            &mut files,
+        );
+        files.sort();
+        assert!(!files.is_empty());
+
+        let mut failures = Vec::new();
+        for path in &files {
+            let source = fs::read_to_string(path).expect("read test script");
+            let expected = Expectations::parse(&source);
+            for &fold_constants in &[true, false] {
+                let options = CompileOptions {
+                    fold_constants,
+                    ..CompileOptions::default()
+                };
+                let (mut vm, output) = vm(options);
+                if let Some(stack_max) = expected.stack_max {
+                    vm.set_stack_max(stack_max);
                 }
-                OpCode::OpLess => {
-                    binary_op!(|l, r| l < r, into_number, "not a number");
+                let error = vm.interpret_source(&source).err().map(|e| e.to_string());
+                let output = take_output(&output);
+                let trace = vm.take_trace();
+                if output != expected.output || error != expected.error {
+                    failures.push(format!(
+                        "{} (fold_constants: {})\n  expected output {:?}, error {:?}\n  got output {:?}, error {:?}",
+                        path.display(),
+                        fold_constants,
+                        expected.output,
+                        expected.error,
+                        output,
+                        error
+                    ));
+                } else if !expected.trace.is_empty() && trace != expected.trace {
+                    failures.push(format!(
+                        "{} (fold_constants: {})\n  expected trace {:?}\n  got trace {:?}",
+                        path.display(),
+                        fold_constants,
+                        expected.trace,
+                        trace
+                    ));
                 }
             }
         }
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
     }
 }